@@ -2,7 +2,20 @@ use std::fs;
 use std::path::Path;
 use std::process;
 use std::process::exit;
-
+use std::time::Duration;
+
+use crate::btrfs_ioctl;
+use crate::config_handler::{read_config_file, ConfigOpts};
+use crate::hooks::{run_hooks, HookStage};
+use crate::mount::{self, MountFlags, MountGuard};
+use crate::package_cache;
+use crate::persistent_paths;
+use crate::process_handler::kill_stray_processes;
+use crate::resource_limits::ResourceLimits;
+use crate::resolv_conf;
+use crate::sandbox::{self, Isolation};
+use crate::snapshot_id;
+use crate::snapshot_metadata::{SnapshotMetadata, SnapshotState};
 use crate::utils::*;
 
 pub fn is_root_user() -> bool {
@@ -11,25 +24,36 @@ pub fn is_root_user() -> bool {
 }
 
 pub fn get_root_subvolume_name() -> Option<String> {
-    let subvols = get_command_output(
-        String::from("btrfs"),
-        Some(&*vec!["subvolume", "list", "/"]),
-    );
+    if let Ok(opts) = read_config_file() {
+        if !opts.root_subvolume.is_empty() {
+            return Some(opts.root_subvolume);
+        }
+    }
 
-    let subvol_output_lines = subvols.split('\n');
+    let subvols = match btrfs_ioctl::list_subvolumes(Path::new("/")) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not list subvolumes: {}", e);
+            return None;
+        }
+    };
 
     // root = Fedora
     // @ = Opensuse, Mint
     let probable_root_names = ["root", "@"];
 
-    for line in subvol_output_lines {
-        let subvol_path = line.split(' ').last().unwrap();
-        if probable_root_names.contains(&subvol_path) {
-            return Some(subvol_path.to_string());
+    for subvol in subvols {
+        if probable_root_names.contains(&subvol.path.as_str()) {
+            return Some(subvol.path);
         }
     }
 
-    None
+    // Otherwise fall back to whatever is mounted on /, provided it sits directly
+    // under the top level
+    match btrfs_ioctl::subvolume_info(Path::new("/")) {
+        Ok(info) if info.parent_id == btrfs_ioctl::BTRFS_FS_TREE_OBJECTID => Some(info.name),
+        _ => None,
+    }
 }
 
 pub fn create_snapshots_dir() {
@@ -45,82 +69,211 @@ pub fn create_snapshots_dir() {
 }
 
 pub fn create_root_snapshot(snapshot_target_dir: &Path) -> std::io::Result<()> {
-    let success = run_command(
-        String::from("btrfs"),
-        Some(&*vec![
-            "subvolume",
-            "snapshot",
-            "/",
-            snapshot_target_dir.to_str().unwrap(),
-        ]),
-    );
-
-    match success {
-        Ok(_output) => {
+    match btrfs_ioctl::create_snapshot(Path::new("/"), snapshot_target_dir) {
+        Ok(()) => {
             println!("Snapshot created at {:?}", snapshot_target_dir.as_os_str());
             Ok(())
         }
         Err(error) => {
-            eprintln!("Error creating snapshot: {:?}", error);
+            eprintln!("Error creating snapshot: {}", error);
             Err(error)
         }
     }
 }
 
+/// The operations which create a new snapshot.
+#[derive(Clone, Copy)]
+pub enum Operation {
+    Update,
+    Install,
+    Exec,
+    /// Making a retained snapshot the root, which runs no command.
+    Promote,
+}
+
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Update => "update",
+            Operation::Install => "install",
+            Operation::Exec => "exec",
+            Operation::Promote => "promote",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Operation> {
+        match name {
+            "update" => Some(Operation::Update),
+            "install" => Some(Operation::Install),
+            "exec" => Some(Operation::Exec),
+            "promote" => Some(Operation::Promote),
+            _ => None,
+        }
+    }
+
+    /// How long the command may run for, from the operation's `TIMEOUT_*`
+    /// setting.
+    fn timeout(&self, opts: &ConfigOpts) -> Option<Duration> {
+        let seconds = match self {
+            Operation::Update => opts.timeout_update,
+            Operation::Install => opts.timeout_install,
+            Operation::Exec => opts.timeout_exec,
+            Operation::Promote => 0,
+        };
+
+        Some(Duration::from_secs(seconds)).filter(|t| !t.is_zero())
+    }
+}
+
 pub fn run_command_in_snapshot_chroot(
     snapshot_target_dir: &Path,
+    operation: Operation,
     command: String,
     args: Option<&[&str]>,
 ) -> std::io::Result<()> {
-    // Chroots dont have /etc/resolv.conf, so network doesnt work
-    // copy from host into snapshot
-    let resolv_loc = format!("{}/etc/resolv.conf", snapshot_target_dir.to_str().unwrap());
-    run_command_and_stream_err(String::from("rm"), vec![resolv_loc.as_str()].as_slice());
-    run_command_and_stream_err(
-        String::from("cp"),
-        vec!["/etc/resolv.conf", resolv_loc.as_str()].as_slice(),
-    );
-
-    let mut chroot_plus_command = vec![snapshot_target_dir.to_str().unwrap(), command.as_str()];
-    if let Some(a) = args {
-        for s in a.iter() {
-            chroot_plus_command.push(*s);
-        }
-    };
+    let config = read_config_file().ok();
+    let isolation = config
+        .as_ref()
+        .map_or(Isolation::Namespaces, |opts| Isolation::from_config(&opts.exec_backend));
+
+    // Dropping these unmounts everything again once the command has finished
+    let mut mounts = Vec::new();
+    for dir in ["/proc", "/sys", "/dev"] {
+        let target = snapshot_target_dir.join(dir.trim_start_matches('/'));
+        mounts.push(mount::bind_mount(Path::new(dir), &target)?);
+    }
 
-    let mount_proc_command = format!("mount --bind /proc {}/proc", snapshot_target_dir.to_str().unwrap());
-    let mount_proc_command_parts = mount_proc_command.split(' ').collect::<Vec<_>>();
+    // The snapshot's resolver config may point at services which are not
+    // running in it, so lend it the host's until the command is done
+    let resolv_conf = resolv_conf::provide_host_resolv_conf(snapshot_target_dir)?;
 
-    let mount_sys_command = format!("mount --bind /sys {}/sys", snapshot_target_dir.to_str().unwrap());
-    let mount_sys_command_parts = mount_sys_command.split(' ').collect::<Vec<_>>();
+    if let Some(opts) = config.as_ref().filter(|opts| package_cache::is_enabled(opts)) {
+        match package_cache::bind_shared_cache(opts, snapshot_target_dir) {
+            Ok(cache_mount) => mounts.push(cache_mount),
+            Err(e) => eprintln!("Could not share the package cache with the snapshot: {}", e),
+        }
+    }
 
-    let mount_dev_command = format!("mount --bind /dev {}/dev", snapshot_target_dir.to_str().unwrap());
-    let mount_dev_command_parts = mount_dev_command.split(' ').collect::<Vec<_>>();
+    if let Some(opts) = config.as_ref() {
+        mounts.extend(persistent_paths::bind_persistent_subvolumes(opts, snapshot_target_dir)?);
+    }
 
-    let was_mounted = run_command(mount_proc_command_parts[0].to_string(), Some(mount_proc_command_parts[1..].iter().as_slice()));
+    let env = match config.as_ref() {
+        Some(opts) => sandbox::snapshot_environment(&opts.env_allowlist),
+        None => sandbox::snapshot_environment(&[]),
+    };
 
-    if let Err(e) = was_mounted {
-        eprintln!("Failed mounting proc!");
-        exit(1);
+    let mut limits = match config.as_ref() {
+        Some(opts) => ResourceLimits::from_config(opts),
+        None => ResourceLimits::none(),
+    };
+    let cgroup = limits.create_cgroup();
+    let timeout = config.as_ref().and_then(|opts| operation.timeout(opts));
+    // Never stop the command for having less space than it was allowed to
+    // start with
+    let free_space = config
+        .as_ref()
+        .map(|opts| match opts.min_free_space {
+            0 => opts.min_free_space_running,
+            start => opts.min_free_space_running.min(start),
+        })
+        .filter(|minimum| *minimum > 0)
+        .map(|minimum| (snapshot_target_dir, minimum));
+
+    let status = sandbox::spawn_in_root(
+        snapshot_target_dir,
+        command.as_str(),
+        args.unwrap_or_default(),
+        &env,
+        isolation,
+        &limits,
+        cgroup.as_ref(),
+    )
+    .and_then(|child| child.wait_with_limits(timeout, free_space));
+
+    let status = match status {
+        Ok(s) if s.success() => {
+            run_hooks(HookStage::InSnapshot, operation, snapshot_target_dir).map(|_| s)
+        }
+        other => other,
+    };
+
+    // Daemons started by the command would otherwise keep the mounts busy
+    for process in kill_stray_processes(snapshot_target_dir, cgroup.as_ref()) {
+        println!(
+            "Killed stray process {} ({}) left running in the snapshot",
+            process.pid, process.name
+        );
+    }
+    drop(cgroup);
+    drop(mounts);
+    drop(resolv_conf);
+
+    let status = status?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            command, status
+        )));
     }
 
-    let was_mounted = run_command(mount_sys_command_parts[0].to_string(), Some(mount_sys_command_parts[1..].iter().as_slice()));
+    Ok(())
+}
 
-    if let Err(e) = was_mounted {
-        eprintln!("Failed mounting sys!");
-        exit(1);
-    }
+/// Delete a snapshot which will not be used, such as one whose command was
+/// interrupted.
+pub fn discard_snapshot(snapshot_path: &Path) -> std::io::Result<()> {
+    btrfs_ioctl::delete_subvolume(snapshot_path)
+}
 
-    let was_mounted = run_command(mount_dev_command_parts[0].to_string(), Some(mount_dev_command_parts[1..].iter().as_slice()));
+/// Whether the filesystem's default subvolume is the root subvolume at
+/// `root_subvol_path`, meaning systems booting without `subvol=` use it.
+fn root_is_default_subvolume(root_subvol_path: &Path) -> bool {
+    let root_id = match btrfs_ioctl::subvolume_info(root_subvol_path) {
+        Ok(info) => info.id,
+        Err(e) => {
+            eprintln!("Could not look up root subvolume: {}", e);
+            return false;
+        }
+    };
 
-    if let Err(e) = was_mounted {
-        eprintln!("Failed mounting dev!");
-        exit(1);
+    match btrfs_ioctl::get_default_subvolume(root_subvol_path) {
+        Ok(default_id) => default_id == root_id,
+        Err(e) => {
+            eprintln!("Could not look up default subvolume: {}", e);
+            false
+        }
     }
+}
 
-    run_command_and_stream_err(String::from("chroot"), chroot_plus_command.as_slice());
+/// Subvolume IDs travel with the subvolume when it is renamed, so after a swap
+/// the default has to be pointed at whatever now lives at the root path.
+fn point_default_subvolume_at(root_subvol_path: &Path) {
+    let set_default = btrfs_ioctl::subvolume_info(root_subvol_path)
+        .and_then(|info| btrfs_ioctl::set_default_subvolume(root_subvol_path, info.id));
 
-    Ok(())
+    if let Err(e) = set_default {
+        eprintln!(
+            "Failed to set the new root as the default subvolume, please run 'btrfs subvolume set-default' manually: {}",
+            e
+        );
+    }
+}
+
+/// Where the top level is temporarily mounted to reach subvolumes kept
+/// outside the root, such as the shared package cache.
+pub const TOP_LEVEL_MOUNT_POINT: &str = "/run/atomic-update/top-level";
+
+/// Mount the top level (subvolid=5) of the root filesystem on `target`, where
+/// the root subvolume and its snapshots can be renamed.
+pub fn mount_top_level(root_partition_device: &str, target: &Path) -> std::io::Result<MountGuard> {
+    mount::mount_filesystem(
+        Path::new(root_partition_device),
+        target,
+        "btrfs",
+        MountFlags::NONE,
+        Some("subvolid=5"),
+    )
 }
 
 pub fn swap_snapshot_to_root(snapshot_path: &Path) {
@@ -138,31 +291,33 @@ pub fn swap_snapshot_to_root(snapshot_path: &Path) {
     let new_rollback_path = format!("/mnt/{}/.au-snapshots/rollback", root_subvol_name);
     let new_rollback_path = Path::new(new_rollback_path.as_str());
 
-    let mount_command = format!("mount -t btrfs -o subvolid=5 {} /mnt", root_partition_device);
-    let mount_command_parts = mount_command.split(' ').collect::<Vec<_>>();
-
-
     println!("Swapping {} to new root, moving current root to /.au-snapshots/rollback", snapshot_path.to_str().unwrap());
 
-    let was_mounted = run_command(mount_command_parts[0].to_string(), Some(mount_command_parts[1..].iter().as_slice()));
+    let _top_level_mount = match mount_top_level(&root_partition_device, Path::new("/mnt")) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
 
-    if let Err(e) = was_mounted {
-        eprintln!("Failed mounting {} to /mnt", root_partition_device);
-        exit(1);
-    }
+    let root_was_default = root_is_default_subvolume(root_subvol_path);
 
+    // From here on signals are only acted upon once every rename is done
     fs::rename(root_subvol_path, rollback_subvol_path).expect("Failed to move subvolume at step 1"); // mv /mnt/root /mnt/rollback
     fs::rename(new_path_to_new_root, root_subvol_path).expect("Failed to move subvolume at step 2"); // mv /mnt/rollback/.au-snapshots/1 /mnt/root
     fs::rename(rollback_subvol_path, new_rollback_path)
         .expect("Failed to move subvolume at step 3"); // mv /mnt/rollback /mnt/root/.au-snapshots/rollback
 
-    let was_unmounted = run_command(String::from("umount"), Some(vec!["/mnt"].as_slice()));
-    if let Err(e) = was_unmounted {
-        eprintln!("Failed unmounting /mnt, please do this manually: {}", e);
+    if root_was_default {
+        point_default_subvolume_at(root_subvol_path);
     }
 }
 
-pub fn swap_rollback_to_root() {
+/// Make the rollback slot the root from the next boot, with the current root
+/// becoming the rollback. `prepare` is given the rollback slot once it is
+/// writable, before anything is moved, and the swap is abandoned if it fails.
+pub fn swap_rollback_to_root(prepare: impl FnOnce(&Path) -> std::io::Result<()>) {
     let root_subvol_name = get_root_subvolume_name()
         .expect("Could not determine root subvolume name - expecting 'root' or '@'");
     let root_partition_device = get_root_partition_device();
@@ -183,79 +338,434 @@ pub fn swap_rollback_to_root() {
     let new_root_temp_subvol_rollback_path = Path::new(new_root_temp_subvol_rollback_path.as_str());
 
     println!("Mounting {} on /mnt", root_partition_device);
-    let mount_command = format!(
-        "mount -t btrfs -o subvolid=5 {} /mnt",
-        root_partition_device
-    );
-    let mount_command_parts = mount_command.split(' ').collect::<Vec<_>>();
-    let was_mounted = run_command(
-        mount_command_parts[0].to_string(),
-        Some(mount_command_parts[1..].iter().as_slice()),
-    );
-
-    if let Err(e) = was_mounted {
-        eprintln!("Failed mounting {} to /mnt: {}", root_partition_device, e);
-        exit(1);
-    }
+    let top_level_mount = match mount_top_level(&root_partition_device, Path::new("/mnt")) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
 
     if !root_subvol_path.exists() || !rollback_subvol_path.exists() {
         eprintln!("Could not find the structure expected in /mnt, aborting");
+        drop(top_level_mount);
         exit(1);
     }
 
-    println!("Swapping rollback to new root, moving current root to /.au-snapshots/rollback");
+    // It becomes the root, and can't be moved to another directory while
+    // read-only
+    if let Err(e) = btrfs_ioctl::set_readonly(rollback_subvol_path, false) {
+        eprintln!("Could not make the rollback slot writable, aborting: {}", e);
+        drop(top_level_mount);
+        exit(1);
+    }
 
-    fs::rename(rollback_subvol_path, new_root_temp_subvol_path)
-        .expect("Failed to move subvolume at step 1"); // mv /mnt/root/.au-snapshots/rollback /mnt/new-root
+    if let Err(e) = prepare(rollback_subvol_path) {
+        eprintln!("Could not prepare the rollback slot, aborting: {}", e);
+        abandon_rollback_swap(rollback_subvol_path, top_level_mount);
+    }
 
-    if new_root_temp_subvol_rollback_path.exists() {
-        println!("Removing {:?}", new_root_temp_subvol_rollback_path.to_str());
-        if let Err(e) = fs::remove_dir_all(new_root_temp_subvol_rollback_path.to_str().unwrap()) {
-            eprintln!("Error clearing old rollback: {:?}", e);
-        }
+    // The rollback slot's own rollback is replaced by the current root, so it
+    // is cleared before anything is moved, while the swap can still be
+    // abandoned. Pinned snapshots in it are kept in the rollback slot.
+    let old_rollback_path = rollback_subvol_path.join(".au-snapshots/rollback");
+    let cleared = if btrfs_ioctl::is_subvolume(&old_rollback_path) {
+        let relative = format!("{}/.au-snapshots/rollback/.au-snapshots/rollback", root_subvol_name);
+        delete_subvolume_tree(Path::new("/mnt"), &relative, &rollback_subvol_path.join(".au-snapshots"))
+    } else if old_rollback_path.exists() {
+        fs::remove_dir_all(&old_rollback_path)
+    } else {
+        Ok(())
+    };
+    if let Err(e) = cleared {
+        eprintln!("Could not clear the old rollback, aborting: {}", e);
+        abandon_rollback_swap(rollback_subvol_path, top_level_mount);
     }
 
+    println!("Swapping rollback to new root, moving current root to /.au-snapshots/rollback");
+
+    let root_was_default = root_is_default_subvolume(root_subvol_path);
+
+    // From here on signals are only acted upon once every rename is done
+    fs::rename(rollback_subvol_path, new_root_temp_subvol_path)
+        .expect("Failed to move subvolume at step 1"); // mv /mnt/root/.au-snapshots/rollback /mnt/new-root
     fs::rename(root_subvol_path, new_root_temp_subvol_rollback_path)
         .expect("Failed to move subvolume at step 2"); // mv /mnt/root /mnt/new-root/.au-snapshots/rollback
     fs::rename(new_root_temp_subvol_path, root_subvol_path)
         .expect("Failed to move subvolume at step 3"); // mv /mnt/new-root /mnt/root
 
-    let was_unmounted = run_command(String::from("umount"), Some(vec!["/mnt"].as_slice()));
-    if let Err(e) = was_unmounted {
-        eprintln!(
-            "Failed unmounting /mnt, please do this manually with 'sudo umount /mnt': {}",
+    if root_was_default {
+        point_default_subvolume_at(root_subvol_path);
+    }
+}
+
+/// Give up on swapping in the rollback slot before anything was moved,
+/// leaving it read-only again.
+fn abandon_rollback_swap(rollback_subvol_path: &Path, top_level_mount: MountGuard) -> ! {
+    if let Err(e) = btrfs_ioctl::set_readonly(rollback_subvol_path, true) {
+        eprintln!("Could not make the rollback slot read-only again: {}", e);
+    }
+    drop(top_level_mount);
+    exit(1);
+}
+
+/// Delete the subvolume at `path`, relative to the top level mounted at
+/// `top_level`, along with every subvolume nested in it, deepest first. A
+/// subvolume can't be deleted while it still contains others. Pinned
+/// snapshots are moved into `keep_dir` under a new number instead, together
+/// with anything nested in them.
+fn delete_subvolume_tree(top_level: &Path, path: &str, keep_dir: &Path) -> std::io::Result<()> {
+    let subvolumes = btrfs_ioctl::list_subvolumes(top_level)?;
+    let nested: Vec<&str> = subvolumes
+        .iter()
+        .map(|s| s.path.as_str())
+        .filter(|p| p.starts_with(&format!("{}/", path)))
+        .collect();
+
+    delete_nested(top_level, path, &nested, keep_dir)
+}
+
+fn delete_nested(top_level: &Path, path: &str, nested: &[&str], keep_dir: &Path) -> std::io::Result<()> {
+    let full_path = top_level.join(path);
+
+    if SnapshotMetadata::is_pinned(&full_path) {
+        let id = snapshot_id::allocate(top_level)?;
+        let kept_path = keep_dir.join(id.to_string());
+
+        // A read-only subvolume can't be moved to another directory
+        let was_readonly = btrfs_ioctl::is_readonly(&full_path)?;
+        if was_readonly {
+            btrfs_ioctl::set_readonly(&full_path, false)?;
+        }
+        fs::rename(&full_path, &kept_path)?;
+        if was_readonly {
+            archive_snapshot(&kept_path);
+        }
+
+        println!("Keeping pinned snapshot {:?} as /.au-snapshots/{}", path, id);
+        return Ok(());
+    }
+
+    // Subvolumes inside it can only be removed while it is writable
+    btrfs_ioctl::set_readonly(&full_path, false)?;
+
+    let prefix = format!("{}/", path);
+    let below: Vec<&str> = nested.iter().copied().filter(|p| p.starts_with(&prefix)).collect();
+    for child in &below {
+        let is_direct = !below.iter().any(|other| child.starts_with(&format!("{}/", other)));
+        if is_direct {
+            delete_nested(top_level, child, nested, keep_dir)?;
+        }
+    }
+
+    println!("Removing {:?}", path);
+    btrfs_ioctl::delete_subvolume(&full_path)
+}
+
+/// Undo a swap made since boot: if the running root is the rollback slot,
+/// make it the next boot's root again and delete the root which replaced it.
+/// Returns false if there was nothing to undo.
+pub fn discard_pending_root() -> std::io::Result<bool> {
+    let root_subvol_name = get_root_subvolume_name()
+        .expect("Could not determine root subvolume name - expecting 'root' or '@'");
+    let root_partition_device = get_root_partition_device();
+    if root_partition_device.as_str() == "" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Failed to detect root partition device, please set it manually in /etc/atomic-update.conf",
+        ));
+    }
+
+    let booted_id = btrfs_ioctl::subvolume_info(Path::new("/"))?.id;
+
+    let root_subvol_path = format!("/mnt/{}", root_subvol_name);
+    let root_subvol_path = Path::new(root_subvol_path.as_str());
+
+    let rollback_subvol_path = format!("/mnt/{}/.au-snapshots/rollback", root_subvol_name);
+    let rollback_subvol_path = Path::new(rollback_subvol_path.as_str());
+
+    let restored_root_temp_path = Path::new("/mnt/restored-root");
+    let discarded_root_temp_path = Path::new("/mnt/discarded-root");
+
+    let top_level_mount = mount_top_level(&root_partition_device, Path::new("/mnt"))?;
+
+    if !btrfs_ioctl::is_subvolume(rollback_subvol_path)
+        || btrfs_ioctl::subvolume_info(rollback_subvol_path)?.id != booted_id
+    {
+        drop(top_level_mount);
+        return Ok(false);
+    }
+
+    println!("Restoring the running root for next boot, discarding the one which replaced it");
+
+    let root_was_default = root_is_default_subvolume(root_subvol_path);
+
+    // From here on signals are only acted upon once every rename is done
+    fs::rename(rollback_subvol_path, restored_root_temp_path)
+        .expect("Failed to move subvolume at step 1"); // mv /mnt/root/.au-snapshots/rollback /mnt/restored-root
+    fs::rename(root_subvol_path, discarded_root_temp_path)
+        .expect("Failed to move subvolume at step 2"); // mv /mnt/root /mnt/discarded-root
+    fs::rename(restored_root_temp_path, root_subvol_path)
+        .expect("Failed to move subvolume at step 3"); // mv /mnt/restored-root /mnt/root
+
+    if root_was_default {
+        point_default_subvolume_at(root_subvol_path);
+    }
+
+    // Keep it as a numbered snapshot if pinned, or if it can't be deleted
+    if SnapshotMetadata::is_pinned(discarded_root_temp_path) {
+        println!("The discarded root is pinned, keeping it");
+        keep_discarded_root(discarded_root_temp_path, root_subvol_path);
+    } else if let Err(e) = btrfs_ioctl::delete_subvolume(discarded_root_temp_path) {
+        eprintln!("Failed deleting the discarded root, keeping it: {}", e);
+        keep_discarded_root(discarded_root_temp_path, root_subvol_path);
+    }
+
+    drop(top_level_mount);
+    Ok(true)
+}
+
+/// Move the root taken out by `discard_pending_root` into the snapshots of
+/// the root at `root_subvol_path` under a new number. The swap is complete by
+/// then, so failing only leaves it at the top level of the filesystem.
+fn keep_discarded_root(discarded_path: &Path, root_subvol_path: &Path) {
+    let kept = snapshot_id::allocate(Path::new("/mnt")).and_then(|id| {
+        fs::rename(discarded_path, root_subvol_path.join(".au-snapshots").join(id.to_string()))?;
+        Ok(id)
+    });
+
+    match kept {
+        Ok(id) => println!("The discarded root has been kept as /.au-snapshots/{}", id),
+        Err(e) => eprintln!(
+            "Could not move the discarded root into /.au-snapshots, it is left at the top level of the filesystem as {:?}: {}",
+            discarded_path.file_name().unwrap(),
             e
-        );
+        ),
     }
 }
 
-pub fn get_next_snapshot_path() -> Result<String, std::io::Error> {
-    let snapshots_path = Path::new("/.au-snapshots");
-    if !snapshots_path.is_dir() {
-        create_snapshots_dir();
-        return Ok(String::from("/.au-snapshots/1"));
+/// A snapshot kept by atomic-update, somewhere below the root subvolume.
+pub struct SnapshotEntry {
+    /// The snapshot's name in its `.au-snapshots` directory: a number, or
+    /// `rollback` for a rollback slot.
+    pub id: String,
+    /// Path relative to the top level of the filesystem.
+    pub path: String,
+    /// The btrfs subvolume ID.
+    pub subvol_id: u64,
+}
+
+/// Find every snapshot kept in the `.au-snapshots` directories of the root
+/// subvolume, including those nested in rollback slots by earlier swaps.
+pub fn list_snapshots() -> std::io::Result<Vec<SnapshotEntry>> {
+    let root_subvol_name = get_root_subvolume_name()
+        .expect("Could not determine root subvolume name - expecting 'root' or '@'");
+
+    let mut snapshots = Vec::new();
+    for subvol in btrfs_ioctl::list_subvolumes(Path::new("/"))? {
+        let nested = match subvol.path.strip_prefix(&format!("{}/", root_subvol_name)) {
+            Some(n) => n,
+            None => continue,
+        };
+
+        // Only .au-snapshots/<id>, possibly below rollback slots
+        let components: Vec<&str> = nested.split('/').collect();
+        let pairs: Vec<&[&str]> = components.chunks(2).collect();
+        let is_snapshot = components.len().is_multiple_of(2)
+            && pairs.iter().all(|pair| pair[0] == ".au-snapshots")
+            && pairs[..pairs.len() - 1].iter().all(|pair| pair[1] == "rollback");
+        if !is_snapshot {
+            continue;
+        }
+
+        snapshots.push(SnapshotEntry {
+            id: components[components.len() - 1].to_string(),
+            path: subvol.path.clone(),
+            subvol_id: subvol.id,
+        });
     }
-    let mut entries = std::fs::read_dir(snapshots_path)?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, std::io::Error>>()?;
 
-    entries.sort();
-    println!("{:?}", entries);
+    snapshots.sort_by_key(|s| s.path.len());
+    Ok(snapshots)
+}
 
-    if entries.is_empty() {
-        return Ok(String::from("/.au-snapshots/1"));
+/// Look up a retained snapshot by its number, or by the number, UUID or a
+/// tag recorded in its metadata. The latter also finds older rollback slots
+/// by the number they were created under, but never the current rollback
+/// slot, which is only used through `rollback`.
+pub fn find_snapshot(id: &str) -> std::io::Result<SnapshotEntry> {
+    let root_subvol_name = get_root_subvolume_name()
+        .expect("Could not determine root subvolume name - expecting 'root' or '@'");
+    let current_rollback = format!("{}/.au-snapshots/rollback", root_subvol_name);
+
+    let (mut matches, others): (Vec<SnapshotEntry>, Vec<SnapshotEntry>) = list_snapshots()?
+        .into_iter()
+        .filter(|s| s.path != current_rollback)
+        .partition(|s| s.id != "rollback" && s.id == id);
+
+    if matches.is_empty() {
+        matches = with_top_level(|top_level| {
+            let answers_to = |path: &str| {
+                SnapshotMetadata::read(&top_level.join(path)).is_ok_and(|m| m.answers_to(id))
+            };
+            if answers_to(&current_rollback) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Snapshot {} is the rollback slot, use 'atomic-update rollback' to return to it", id),
+                ));
+            }
+
+            Ok(others.into_iter().filter(|s| answers_to(&s.path)).collect())
+        })?;
+    }
+
+    match matches.len() {
+        0 => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("There is no snapshot {}", id),
+        )),
+        1 => Ok(matches.remove(0)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Snapshot {} is ambiguous, it could be any of: {}",
+                id,
+                matches.iter().map(|s| s.path.as_str()).collect::<Vec<_>>().join(", ")
+            ),
+        )),
+    }
+}
+
+/// Whether the root subvolume, which is booted next, is the one running, i.e.
+/// nothing has been swapped in since boot.
+pub fn next_boot_root_is_running() -> std::io::Result<bool> {
+    let root_subvol_name = get_root_subvolume_name()
+        .expect("Could not determine root subvolume name - expecting 'root' or '@'");
+    let booted_id = btrfs_ioctl::subvolume_info(Path::new("/"))?.id;
+
+    Ok(btrfs_ioctl::list_subvolumes(Path::new("/"))?
+        .iter()
+        .any(|subvol| subvol.path == root_subvol_name && subvol.id == booted_id))
+}
+
+/// Run `f` with the top level of the filesystem mounted, passing it the mount
+/// point, for reaching snapshots wherever they are nested.
+pub fn with_top_level<T>(f: impl FnOnce(&Path) -> std::io::Result<T>) -> std::io::Result<T> {
+    let root_partition_device = get_root_partition_device();
+    if root_partition_device.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Failed to detect root partition device, please set ROOT_PARTITION in /etc/atomic-update.conf",
+        ));
+    }
+
+    let top_level_path = Path::new(TOP_LEVEL_MOUNT_POINT);
+    fs::create_dir_all(top_level_path)?;
+    let _top_level = mount_top_level(&root_partition_device, top_level_path)?;
+
+    f(top_level_path)
+}
+
+/// Take a new writable snapshot at `dest` of the retained snapshot at
+/// `source_path` (relative to the top level), leaving the original in place.
+pub fn copy_snapshot(source_path: &str, dest: &Path) -> std::io::Result<()> {
+    with_top_level(|top_level| {
+        btrfs_ioctl::create_snapshot(&top_level.join(source_path), dest)?;
+        println!("Snapshot created at {:?}", dest.as_os_str());
+        Ok(())
+    })
+}
+
+/// Delete the retained snapshot at `path`, relative to the top level.
+pub fn delete_snapshot(path: &str) -> std::io::Result<()> {
+    with_top_level(|top_level| {
+        let snapshot_path = top_level.join(path);
+
+        // The .au-snapshots directory may belong to a read-only rollback slot
+        let parent = snapshot_path.parent().and_then(Path::parent).unwrap_or(top_level);
+        with_writable(parent, || btrfs_ioctl::delete_subvolume(&snapshot_path))
+    })
+}
+
+/// Run `f` with the subvolume containing `path` writable, making it
+/// read-only again afterwards if it was.
+pub fn with_writable<T>(path: &Path, f: impl FnOnce() -> std::io::Result<T>) -> std::io::Result<T> {
+    let was_readonly = btrfs_ioctl::is_readonly(path)?;
+    if was_readonly {
+        btrfs_ioctl::set_readonly(path, false)?;
+    }
+
+    let result = f();
+
+    if was_readonly {
+        if let Err(e) = btrfs_ioctl::set_readonly(path, true) {
+            eprintln!("Failed making {:?} read-only again: {}", path, e);
+        }
     }
 
-    for entry in entries.iter().rev() {
-        let entry_str = entry.to_str().unwrap();
-        let entry_folder = entry_str.split('/').last().unwrap();
-        if let Ok(num) = entry_folder.parse::<i32>() {
-            let next_dir = format!("/.au-snapshots/{}", num + 1);
-            if !Path::new(&next_dir).exists() {
-                return Ok(next_dir);
+    result
+}
+
+/// Make the snapshot at `path` read-only, now that nothing should change it
+/// until it is promoted.
+pub fn archive_snapshot(path: &Path) {
+    if let Err(e) = btrfs_ioctl::set_readonly(path, true) {
+        eprintln!("Could not make {:?} read-only: {}", path, e);
+    }
+}
+
+/// Make every retained snapshot read-only, apart from the running root and
+/// snapshots still being built or waiting to be swapped in. This covers the
+/// rollback slot once it is no longer booted, which can't be done at the
+/// time of the swap.
+pub fn archive_snapshots() -> std::io::Result<()> {
+    let booted_id = btrfs_ioctl::subvolume_info(Path::new("/"))?.id;
+    let snapshots = list_snapshots()?;
+
+    with_top_level(|top_level| {
+        for snapshot in snapshots {
+            if snapshot.subvol_id == booted_id {
+                continue;
+            }
+
+            let path = top_level.join(&snapshot.path);
+            match btrfs_ioctl::is_readonly(&path) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Could not check whether {:?} is read-only: {}", path, e);
+                    continue;
+                }
+            }
+
+            let in_use = SnapshotMetadata::read(&path).is_ok_and(|m| {
+                matches!(
+                    m.state,
+                    SnapshotState::Building | SnapshotState::Ready | SnapshotState::Staged
+                )
+            });
+            if !in_use {
+                archive_snapshot(&path);
             }
         }
+
+        Ok(())
+    })
+}
+
+/// A path in /.au-snapshots for a new snapshot, under a number which has
+/// never been used before.
+pub fn get_next_snapshot_path() -> Result<String, std::io::Error> {
+    let snapshots_path = Path::new("/.au-snapshots");
+    if !snapshots_path.is_dir() {
+        create_snapshots_dir();
     }
 
-    return Ok(String::from("/.au-snapshots/1"));
+    with_top_level(|top_level| loop {
+        // Only a snapshot created by hand could be in the way
+        let next_dir = format!("/.au-snapshots/{}", snapshot_id::allocate(top_level)?);
+        if !Path::new(&next_dir).exists() {
+            return Ok(next_dir);
+        }
+    })
 }
@@ -0,0 +1,833 @@
+//! Thin wrappers around the btrfs ioctls atomic-update needs.
+//!
+//! These replace shelling out to `btrfs-progs`, so the only requirement on the
+//! target system is a kernel with btrfs support. Every failure is reported as
+//! an `std::io::Error` carrying the errno of the failing ioctl.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_ulong};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+const BTRFS_IOCTL_MAGIC: u64 = 0x94;
+
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+const BTRFS_INO_LOOKUP_PATH_MAX: usize = 4080;
+const BTRFS_SEARCH_ARGS_BUFSIZE: usize = 4096 - size_of::<SearchKey>();
+
+pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
+const BTRFS_ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+/// Leave file contents out of a send stream, only describing what changed.
+pub const BTRFS_SEND_FLAG_NO_FILE_DATA: u64 = 0x1;
+
+const BTRFS_DIR_ITEM_KEY: u32 = 84;
+const BTRFS_ROOT_BACKREF_KEY: u32 = 144;
+const BTRFS_QGROUP_STATUS_KEY: u32 = 240;
+const BTRFS_QGROUP_INFO_KEY: u32 = 242;
+
+const BTRFS_QUOTA_CTL_ENABLE: u64 = 1;
+
+const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+/// The space info of the global block reserve, which is not a real block group.
+const BTRFS_SPACE_INFO_GLOBAL_RSV: u64 = 1 << 49;
+
+const ENODEV: i32 = 19;
+
+const fn ioc(dir: u64, nr: u64, size: usize) -> c_ulong {
+    ((dir << 30) | ((size as u64) << 16) | (BTRFS_IOCTL_MAGIC << 8) | nr) as c_ulong
+}
+
+const fn iow(nr: u64, size: usize) -> c_ulong {
+    ioc(1, nr, size)
+}
+
+const fn ior(nr: u64, size: usize) -> c_ulong {
+    ioc(2, nr, size)
+}
+
+const fn iowr(nr: u64, size: usize) -> c_ulong {
+    ioc(3, nr, size)
+}
+
+const BTRFS_IOC_SUBVOL_CREATE: c_ulong = iow(14, size_of::<VolArgs>());
+const BTRFS_IOC_SNAP_DESTROY: c_ulong = iow(15, size_of::<VolArgs>());
+const BTRFS_IOC_TREE_SEARCH: c_ulong = iowr(17, size_of::<SearchArgs>());
+const BTRFS_IOC_INO_LOOKUP: c_ulong = iowr(18, size_of::<InoLookupArgs>());
+const BTRFS_IOC_DEFAULT_SUBVOL: c_ulong = iow(19, size_of::<u64>());
+const BTRFS_IOC_SNAP_CREATE_V2: c_ulong = iow(23, size_of::<VolArgsV2>());
+const BTRFS_IOC_SEND: c_ulong = iow(38, size_of::<SendArgs>());
+const BTRFS_IOC_SUBVOL_GETFLAGS: c_ulong = ior(25, size_of::<u64>());
+const BTRFS_IOC_SUBVOL_SETFLAGS: c_ulong = iow(26, size_of::<u64>());
+const BTRFS_IOC_QUOTA_CTL: c_ulong = iowr(40, size_of::<QuotaCtlArgs>());
+const BTRFS_IOC_SPACE_INFO: c_ulong = iowr(20, size_of::<SpaceArgs>());
+const BTRFS_IOC_DEV_INFO: c_ulong = iowr(30, size_of::<DevInfoArgs>());
+const BTRFS_IOC_FS_INFO: c_ulong = ior(31, size_of::<FsInfoArgs>());
+const BTRFS_IOC_GET_SUBVOL_INFO: c_ulong = ior(60, size_of::<GetSubvolInfoArgs>());
+
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; BTRFS_SEARCH_ARGS_BUFSIZE],
+}
+
+const SEARCH_HEADER_LEN: usize = 32;
+
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; BTRFS_INO_LOOKUP_PATH_MAX],
+}
+
+#[repr(C)]
+struct IoctlTimespec {
+    sec: u64,
+    nsec: u32,
+}
+
+#[repr(C)]
+struct GetSubvolInfoArgs {
+    treeid: u64,
+    name: [u8; 256],
+    parent_id: u64,
+    dirid: u64,
+    generation: u64,
+    flags: u64,
+    uuid: [u8; 16],
+    parent_uuid: [u8; 16],
+    received_uuid: [u8; 16],
+    ctransid: u64,
+    otransid: u64,
+    stransid: u64,
+    rtransid: u64,
+    ctime: IoctlTimespec,
+    otime: IoctlTimespec,
+    stime: IoctlTimespec,
+    rtime: IoctlTimespec,
+    reserved: [u64; 8],
+}
+
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+#[repr(C)]
+struct SendArgs {
+    send_fd: i64,
+    clone_sources_count: u64,
+    clone_sources: *const u64,
+    parent_root: u64,
+    flags: u64,
+    version: u32,
+    reserved: [u8; 28],
+}
+
+/// The header of `struct btrfs_ioctl_space_args`, which is followed by
+/// `space_slots` entries of flags, total bytes and used bytes.
+#[repr(C)]
+struct SpaceArgs {
+    space_slots: u64,
+    total_spaces: u64,
+}
+
+#[repr(C)]
+struct DevInfoArgs {
+    devid: u64,
+    uuid: [u8; 16],
+    bytes_used: u64,
+    total_bytes: u64,
+    unused: [u64; 379],
+    path: [u8; 1024],
+}
+
+#[repr(C)]
+struct QuotaCtlArgs {
+    cmd: u64,
+    status: u64,
+}
+
+/// Details of a single subvolume, as reported by `BTRFS_IOC_GET_SUBVOL_INFO`.
+pub struct SubvolumeInfo {
+    pub id: u64,
+    pub parent_id: u64,
+    pub name: String,
+    pub uuid: String,
+    /// UUID of the subvolume this is a snapshot of, all zeroes if none.
+    pub parent_uuid: String,
+    /// When the subvolume was created, in seconds since the epoch.
+    pub created: u64,
+}
+
+/// A subvolume found by searching the root tree, with its path relative to
+/// the top level (subvolid=5) of the filesystem.
+pub struct SubvolumeEntry {
+    pub id: u64,
+    pub path: String,
+}
+
+/// Space accounted to a subvolume by its level 0 quota group.
+pub struct QgroupUsage {
+    /// Bytes referenced by the subvolume, shared or not.
+    pub referenced: u64,
+    /// Bytes referenced by this subvolume only.
+    pub exclusive: u64,
+}
+
+/// One item returned by `BTRFS_IOC_TREE_SEARCH`.
+struct SearchItem {
+    objectid: u64,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+fn ioctl_error(operation: &str, path: &Path) -> io::Error {
+    let os_error = io::Error::last_os_error();
+    io::Error::new(
+        os_error.kind(),
+        format!("{} failed on {:?}: {}", operation, path, os_error),
+    )
+}
+
+/// Zero-initialise one of the ioctl argument structs above.
+///
+/// All of them are plain old data, for which an all-zero bit pattern is valid.
+fn zeroed<T>() -> T {
+    unsafe { std::mem::zeroed() }
+}
+
+fn copy_name(dest: &mut [u8], name: &OsStr) -> io::Result<()> {
+    let bytes = name.as_bytes();
+    if bytes.len() >= dest.len() || bytes.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid subvolume name {:?}", name),
+        ));
+    }
+    dest[..bytes.len()].copy_from_slice(bytes);
+
+    Ok(())
+}
+
+fn c_string_lossy(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Format a UUID the way `blkid` and /dev/disk/by-uuid do.
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+/// Split a path into its (opened) parent directory and final component.
+fn open_parent(path: &Path) -> io::Result<(File, &OsStr)> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} has no final path component", path),
+        )
+    })?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    Ok((File::open(parent)?, name))
+}
+
+/// Returns true if `path` is the top directory of a btrfs subvolume.
+pub fn is_subvolume(path: &Path) -> bool {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta.is_dir() && meta.ino() == BTRFS_FIRST_FREE_OBJECTID,
+        Err(_) => false,
+    }
+}
+
+/// Create a snapshot of the subvolume at `source` at the new path `dest`.
+fn snapshot_with_flags(source: &Path, dest: &Path, flags: u64) -> io::Result<()> {
+    let source_dir = File::open(source)?;
+    let (dest_parent, dest_name) = open_parent(dest)?;
+
+    let mut args: VolArgsV2 = zeroed();
+    args.fd = source_dir.as_raw_fd() as i64;
+    args.flags = flags;
+    copy_name(&mut args.name, dest_name)?;
+
+    let ret = unsafe { ioctl(dest_parent.as_raw_fd(), BTRFS_IOC_SNAP_CREATE_V2, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Snapshot creation", dest));
+    }
+
+    Ok(())
+}
+
+pub fn create_snapshot(source: &Path, dest: &Path) -> io::Result<()> {
+    snapshot_with_flags(source, dest, 0)
+}
+
+/// Snapshot `source` at `dest`, which can't be changed afterwards. Only
+/// read-only subvolumes can be sent.
+pub fn create_readonly_snapshot(source: &Path, dest: &Path) -> io::Result<()> {
+    snapshot_with_flags(source, dest, BTRFS_SUBVOL_RDONLY)
+}
+
+/// Create a new, empty subvolume at `path`.
+pub fn create_subvolume(path: &Path) -> io::Result<()> {
+    let (parent, name) = open_parent(path)?;
+
+    let mut args: VolArgs = zeroed();
+    copy_name(&mut args.name, name)?;
+
+    let ret = unsafe { ioctl(parent.as_raw_fd(), BTRFS_IOC_SUBVOL_CREATE, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Subvolume creation", path));
+    }
+
+    Ok(())
+}
+
+/// Delete the subvolume at `path`. Fails with `ENOTEMPTY` if it still contains
+/// other subvolumes.
+pub fn delete_subvolume(path: &Path) -> io::Result<()> {
+    let (parent, name) = open_parent(path)?;
+
+    let mut args: VolArgs = zeroed();
+    copy_name(&mut args.name, name)?;
+
+    let ret = unsafe { ioctl(parent.as_raw_fd(), BTRFS_IOC_SNAP_DESTROY, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Subvolume deletion", path));
+    }
+
+    Ok(())
+}
+
+/// Look up the subvolume containing `path`.
+pub fn subvolume_info(path: &Path) -> io::Result<SubvolumeInfo> {
+    let dir = File::open(path)?;
+
+    let mut args: GetSubvolInfoArgs = zeroed();
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_GET_SUBVOL_INFO, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Subvolume lookup", path));
+    }
+
+    Ok(SubvolumeInfo {
+        id: args.treeid,
+        parent_id: args.parent_id,
+        name: c_string_lossy(&args.name),
+        uuid: format_uuid(&args.uuid),
+        parent_uuid: format_uuid(&args.parent_uuid),
+        created: args.otime.sec,
+    })
+}
+
+/// Run a tree search over every item of `item_type` in the root tree whose
+/// objectid lies within the given range.
+fn search_root_tree(
+    fs_path: &Path,
+    min_objectid: u64,
+    max_objectid: u64,
+    item_type: u32,
+) -> io::Result<Vec<SearchItem>> {
+    search_tree(
+        fs_path,
+        BTRFS_ROOT_TREE_OBJECTID,
+        min_objectid,
+        max_objectid,
+        item_type,
+    )
+}
+
+fn search_tree(
+    fs_path: &Path,
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    item_type: u32,
+) -> io::Result<Vec<SearchItem>> {
+    let dir = File::open(fs_path)?;
+    let mut items = Vec::new();
+
+    let mut args: SearchArgs = zeroed();
+    args.key.tree_id = tree_id;
+    args.key.min_objectid = min_objectid;
+    args.key.max_objectid = max_objectid;
+    args.key.min_type = item_type;
+    args.key.max_type = item_type;
+    args.key.max_offset = u64::MAX;
+    args.key.max_transid = u64::MAX;
+
+    loop {
+        args.key.nr_items = 4096;
+        let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_TREE_SEARCH, &mut args) };
+        if ret < 0 {
+            return Err(ioctl_error("Tree search", fs_path));
+        }
+        if args.key.nr_items == 0 {
+            break;
+        }
+
+        let mut pos = 0;
+        let mut last = (0, 0, 0);
+        for _ in 0..args.key.nr_items {
+            let header = &args.buf[pos..pos + SEARCH_HEADER_LEN];
+            let objectid = read_u64(header, 8);
+            let offset = read_u64(header, 16);
+            let key_type = read_u32(header, 24);
+            let len = read_u32(header, 28) as usize;
+            pos += SEARCH_HEADER_LEN;
+
+            // The kernel returns every key between the minimum and maximum,
+            // which includes other types of item for objectids in between
+            if key_type == item_type {
+                items.push(SearchItem {
+                    objectid,
+                    offset,
+                    data: args.buf[pos..pos + len].to_vec(),
+                });
+            }
+            pos += len;
+            last = (objectid, key_type, offset);
+        }
+
+        // Resume the search just after the last key we were given
+        let (objectid, key_type, offset) = last;
+        if key_type < item_type {
+            args.key.min_objectid = objectid;
+            args.key.min_offset = 0;
+        } else if key_type == item_type && offset < u64::MAX {
+            args.key.min_objectid = objectid;
+            args.key.min_offset = offset + 1;
+        } else if objectid < max_objectid {
+            args.key.min_objectid = objectid + 1;
+            args.key.min_offset = 0;
+        } else {
+            break;
+        }
+    }
+
+    Ok(items)
+}
+
+/// Resolve the path of directory `dirid` inside subvolume `treeid`.
+fn ino_lookup(fs_path: &Path, treeid: u64, dirid: u64) -> io::Result<String> {
+    let dir = File::open(fs_path)?;
+
+    let mut args: InoLookupArgs = zeroed();
+    args.treeid = treeid;
+    args.objectid = dirid;
+
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_INO_LOOKUP, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Inode lookup", fs_path));
+    }
+
+    Ok(c_string_lossy(&args.name))
+}
+
+/// List every subvolume on the filesystem containing `fs_path`, the native
+/// equivalent of `btrfs subvolume list`.
+pub fn list_subvolumes(fs_path: &Path) -> io::Result<Vec<SubvolumeEntry>> {
+    // (id, parent id, directory in parent, name)
+    let mut refs = Vec::new();
+    for item in search_root_tree(
+        fs_path,
+        BTRFS_FIRST_FREE_OBJECTID,
+        BTRFS_LAST_FREE_OBJECTID,
+        BTRFS_ROOT_BACKREF_KEY,
+    )? {
+        // struct btrfs_root_ref { __le64 dirid; __le64 sequence; __le16 name_len; } + name
+        // Skip items too short to be one, rather than reading past them
+        if item.data.len() < 18 {
+            continue;
+        }
+        let dirid = read_u64(&item.data, 0);
+        let name_len = read_u16(&item.data, 16) as usize;
+        let name = match item.data.get(18..18 + name_len) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => continue,
+        };
+        refs.push((item.objectid, item.offset, dirid, name));
+    }
+
+    let mut entries = Vec::new();
+    for (id, _, _, _) in refs.iter() {
+        // Walk up the parents until reaching the top level
+        let mut components = Vec::new();
+        let mut current = *id;
+        while let Some((_, parent_id, dirid, name)) = refs.iter().find(|r| r.0 == current) {
+            let dir_path = ino_lookup(fs_path, *parent_id, *dirid)?;
+            components.push(format!("{}{}", dir_path, name));
+            if *parent_id == BTRFS_FS_TREE_OBJECTID {
+                break;
+            }
+            current = *parent_id;
+        }
+        components.reverse();
+
+        entries.push(SubvolumeEntry {
+            id: *id,
+            path: components.join("/"),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Return the ID of the subvolume mounted when no `subvol=` option is given.
+pub fn get_default_subvolume(fs_path: &Path) -> io::Result<u64> {
+    for item in search_root_tree(
+        fs_path,
+        BTRFS_ROOT_TREE_DIR_OBJECTID,
+        BTRFS_ROOT_TREE_DIR_OBJECTID,
+        BTRFS_DIR_ITEM_KEY,
+    )? {
+        // struct btrfs_dir_item is packed: a 17 byte location key, transid,
+        // data_len, name_len and type, followed by the name.
+        if item.data.len() < 30 {
+            continue;
+        }
+        let name_len = read_u16(&item.data, 27) as usize;
+        if item.data.get(30..30 + name_len) == Some(b"default".as_slice()) {
+            return Ok(read_u64(&item.data, 0));
+        }
+    }
+
+    // Filesystems which never had a default set use the top level
+    Ok(BTRFS_FS_TREE_OBJECTID)
+}
+
+/// Make subvolume `id` the one mounted when no `subvol=` option is given.
+pub fn set_default_subvolume(fs_path: &Path, id: u64) -> io::Result<()> {
+    let dir = File::open(fs_path)?;
+
+    let mut subvol_id = id;
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_DEFAULT_SUBVOL, &mut subvol_id) };
+    if ret < 0 {
+        return Err(ioctl_error("Setting default subvolume", fs_path));
+    }
+
+    Ok(())
+}
+
+/// Return the UUID of the filesystem containing `path`, as used in `UUID=`
+/// entries of /etc/fstab.
+pub fn filesystem_uuid(path: &Path) -> io::Result<String> {
+    let dir = File::open(path)?;
+
+    let mut args: FsInfoArgs = zeroed();
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_FS_INFO, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Filesystem lookup", path));
+    }
+
+    Ok(format_uuid(&args.fsid))
+}
+
+/// Space left for new data and metadata on a btrfs filesystem, counting both
+/// what is free in the chunks already allocated to each and the space not yet
+/// allocated to any chunk, divided by the copies the profile keeps.
+pub struct FreeSpace {
+    pub data: u64,
+    pub metadata: u64,
+}
+
+/// How many bytes of raw device space a byte stored with `flags` takes up.
+fn profile_copies(flags: u64) -> u64 {
+    if flags & BTRFS_BLOCK_GROUP_RAID1C4 != 0 {
+        4
+    } else if flags & BTRFS_BLOCK_GROUP_RAID1C3 != 0 {
+        3
+    } else if flags & (BTRFS_BLOCK_GROUP_RAID1 | BTRFS_BLOCK_GROUP_DUP | BTRFS_BLOCK_GROUP_RAID10) != 0 {
+        2
+    } else {
+        1
+    }
+}
+
+/// The raw device space of every device of the filesystem at `path` which is
+/// not yet allocated to any chunk.
+fn unallocated_space(dir: &File, path: &Path) -> io::Result<u64> {
+    let mut fs_info: FsInfoArgs = zeroed();
+    if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_FS_INFO, &mut fs_info) } < 0 {
+        return Err(ioctl_error("Filesystem lookup", path));
+    }
+
+    let mut unallocated = 0;
+    for devid in 1..=fs_info.max_id {
+        let mut args: Box<DevInfoArgs> = Box::new(zeroed());
+        args.devid = devid;
+        if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_DEV_INFO, &mut *args as *mut DevInfoArgs) } < 0 {
+            // Device IDs of removed devices are not reused
+            if io::Error::last_os_error().raw_os_error() == Some(ENODEV) {
+                continue;
+            }
+            return Err(ioctl_error("Device lookup", path));
+        }
+        unallocated += args.total_bytes.saturating_sub(args.bytes_used);
+    }
+
+    Ok(unallocated)
+}
+
+/// Work out the space left on the btrfs filesystem containing `path`, the
+/// way `btrfs filesystem usage` estimates it.
+pub fn free_space(path: &Path) -> io::Result<FreeSpace> {
+    let dir = File::open(path)?;
+
+    // Ask how many space infos there are, then fetch them all
+    let mut header: SpaceArgs = zeroed();
+    if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SPACE_INFO, &mut header) } < 0 {
+        return Err(ioctl_error("Space info", path));
+    }
+    let slots = header.total_spaces as usize;
+    let mut buf = vec![0u64; 2 + 3 * slots];
+    buf[0] = slots as u64;
+    if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SPACE_INFO, buf.as_mut_ptr()) } < 0 {
+        return Err(ioctl_error("Space info", path));
+    }
+    let returned = (buf[1] as usize).min(slots);
+
+    let unallocated = unallocated_space(&dir, path)?;
+
+    let mut free = FreeSpace {
+        data: 0,
+        metadata: 0,
+    };
+    let mut data_copies = 1;
+    let mut metadata_copies = 1;
+    let mut reserved = 0;
+    for space in buf[2..2 + 3 * returned].chunks_exact(3) {
+        let (flags, total, used) = (space[0], space[1], space[2]);
+        if flags & BTRFS_SPACE_INFO_GLOBAL_RSV != 0 {
+            reserved += total;
+            continue;
+        }
+        // Mixed block groups hold both, and count towards both
+        if flags & BTRFS_BLOCK_GROUP_DATA != 0 {
+            free.data += total.saturating_sub(used);
+            data_copies = data_copies.max(profile_copies(flags));
+        }
+        if flags & BTRFS_BLOCK_GROUP_METADATA != 0 {
+            free.metadata += total.saturating_sub(used);
+            metadata_copies = metadata_copies.max(profile_copies(flags));
+        }
+    }
+
+    free.data += unallocated / data_copies;
+    // The global reserve is only for the kernel's own use once space runs out
+    free.metadata = (free.metadata + unallocated / metadata_copies).saturating_sub(reserved);
+
+    Ok(free)
+}
+
+/// Write a send stream for the read-only subvolume at `path` to `out`, as
+/// `btrfs send` would, describing it relative to the read-only subvolume
+/// `parent` when given.
+pub fn send_subvolume_to(path: &Path, parent: Option<&Path>, flags: u64, out: &impl AsRawFd) -> io::Result<()> {
+    let dir = File::open(path)?;
+    let parent_root = match parent {
+        Some(p) => subvolume_info(p)?.id,
+        None => 0,
+    };
+
+    let mut args: SendArgs = zeroed();
+    args.send_fd = out.as_raw_fd() as i64;
+    args.parent_root = parent_root;
+    args.flags = flags;
+
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SEND, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Send", path));
+    }
+
+    Ok(())
+}
+
+/// Generate a send stream in memory, see `send_subvolume_to`.
+pub fn send_subvolume(path: &Path, parent: Option<&Path>, flags: u64) -> io::Result<Vec<u8>> {
+    let (mut reader, writer) = io::pipe()?;
+
+    // The ioctl blocks until the whole stream has been written, so it has to
+    // be read from the other end at the same time
+    let read_stream = std::thread::spawn(move || {
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream).map(|_| stream)
+    });
+
+    let sent = send_subvolume_to(path, parent, flags, &writer);
+    drop(writer);
+
+    let stream = read_stream.join().unwrap()?;
+    sent.map(|_| stream)
+}
+
+/// Whether the subvolume at `path` is read-only.
+pub fn is_readonly(path: &Path) -> io::Result<bool> {
+    let dir = File::open(path)?;
+
+    let mut flags: u64 = 0;
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags) };
+    if ret < 0 {
+        return Err(ioctl_error("Reading subvolume flags", path));
+    }
+
+    Ok(flags & BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Make the subvolume at `path` read-only, or writable again.
+pub fn set_readonly(path: &Path, readonly: bool) -> io::Result<()> {
+    let dir = File::open(path)?;
+
+    let mut flags: u64 = 0;
+    if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_GETFLAGS, &mut flags) } < 0 {
+        return Err(ioctl_error("Reading subvolume flags", path));
+    }
+
+    if readonly {
+        flags |= BTRFS_SUBVOL_RDONLY;
+    } else {
+        flags &= !BTRFS_SUBVOL_RDONLY;
+    }
+    if unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_SETFLAGS, &mut flags) } < 0 {
+        return Err(ioctl_error("Setting subvolume flags", path));
+    }
+
+    Ok(())
+}
+
+/// Turn on quota groups, which account the space used by each subvolume. The
+/// kernel scans existing data in the background afterwards.
+pub fn enable_quotas(fs_path: &Path) -> io::Result<()> {
+    let dir = File::open(fs_path)?;
+
+    let mut args: QuotaCtlArgs = zeroed();
+    args.cmd = BTRFS_QUOTA_CTL_ENABLE;
+    let ret = unsafe { ioctl(dir.as_raw_fd(), BTRFS_IOC_QUOTA_CTL, &mut args) };
+    if ret < 0 {
+        return Err(ioctl_error("Enabling quotas", fs_path));
+    }
+
+    Ok(())
+}
+
+/// Whether quota groups are enabled on the filesystem containing `fs_path`.
+pub fn quotas_enabled(fs_path: &Path) -> bool {
+    search_tree(fs_path, BTRFS_QUOTA_TREE_OBJECTID, 0, 0, BTRFS_QGROUP_STATUS_KEY)
+        .is_ok_and(|items| !items.is_empty())
+}
+
+/// Read the space used by every subvolume from the quota tree, by subvolume
+/// ID. Fails if quotas are not enabled.
+pub fn qgroup_usage(fs_path: &Path) -> io::Result<HashMap<u64, QgroupUsage>> {
+    let mut usage = HashMap::new();
+
+    for item in search_tree(fs_path, BTRFS_QUOTA_TREE_OBJECTID, 0, 0, BTRFS_QGROUP_INFO_KEY)? {
+        // The offset is the qgroup ID, level in the top 16 bits
+        if item.offset >> 48 != 0 {
+            continue;
+        }
+
+        // struct btrfs_qgroup_info_item { generation, rfer, rfer_cmpr, excl, excl_cmpr }
+        usage.insert(
+            item.offset,
+            QgroupUsage {
+                referenced: read_u64(&item.data, 8),
+                exclusive: read_u64(&item.data, 24),
+            },
+        );
+    }
+
+    Ok(usage)
+}
@@ -4,6 +4,7 @@ use std::io::Write;
 use std::path::Path;
 
 use crate::btrfs_handler::get_root_subvolume_name;
+use crate::health_check::BUILTIN_CHECKS;
 use crate::utils::*;
 
 pub struct ConfigOpts {
@@ -13,6 +14,52 @@ pub struct ConfigOpts {
     pub(crate) yes_flag: String,
     pub(crate) root_partition: String,
     pub(crate) root_subvolume: String,
+    pub(crate) exec_backend: String,
+    pub(crate) package_cache: String,
+    pub(crate) download_flag: String,
+    pub(crate) prefetch_packages: bool,
+    pub(crate) env_allowlist: Vec<String>,
+    pub(crate) timeout_update: u64,
+    pub(crate) timeout_install: u64,
+    pub(crate) timeout_exec: u64,
+    pub(crate) cpu_weight: String,
+    pub(crate) memory_max: String,
+    pub(crate) io_weight: String,
+    pub(crate) nice: String,
+    pub(crate) io_class: String,
+    pub(crate) health_checks: Vec<String>,
+    pub(crate) merge_etc: bool,
+    pub(crate) persistent_paths: Vec<String>,
+    pub(crate) defer_swap: bool,
+    pub(crate) snapshot_space_warn: u64,
+    pub(crate) min_free_space: u64,
+    pub(crate) min_free_space_running: u64,
+}
+
+/// Variables passed into snapshots when ENV_ALLOWLIST is not set, so package
+/// managers keep working behind a proxy.
+const DEFAULT_ENV_ALLOWLIST: [&str; 8] = [
+    "http_proxy",
+    "https_proxy",
+    "ftp_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "FTP_PROXY",
+    "NO_PROXY",
+];
+
+/// Where each package manager keeps downloaded packages, and the flag which
+/// makes it download packages without installing them.
+fn package_cache_defaults(package_manager: &str) -> (&'static str, &'static str) {
+    match package_manager {
+        "apt" => ("/var/cache/apt/archives", "--download-only"),
+        "zypper" => ("/var/cache/zypp/packages", "--download-only"),
+        "dnf" if Path::new("/var/cache/libdnf5").is_dir() => ("/var/cache/libdnf5", "--downloadonly"),
+        "dnf" => ("/var/cache/dnf", "--downloadonly"),
+        "pacman" => ("/var/cache/pacman/pkg", "-w"),
+        _ => ("", ""),
+    }
 }
 
 fn populate_config_file_with_defaults() {
@@ -73,10 +120,24 @@ fn populate_config_file_with_defaults() {
     let root_partition = get_root_partition_device();
 
     if !package_manager.is_empty() {
-        let config_contents = format!(
+        let mut config_contents = format!(
             "PACKAGE_MANAGER {}\nUPDATE_COMMAND {}\nINSTALL_COMMAND {}\nYES_FLAG {}\n",
             package_manager, update_command, install_command, yes_flag
         );
+
+        let (package_cache, download_flag) = package_cache_defaults(package_manager);
+        if !package_cache.is_empty() {
+            config_contents += &format!(
+                "PACKAGE_CACHE {}\nDOWNLOAD_FLAG {}\n",
+                package_cache, download_flag
+            );
+        }
+
+        // Only new configs check free space, so that commands which worked
+        // before keep working after an upgrade. The lower limit while running
+        // leaves room for the packages downloaded.
+        config_contents += "MIN_FREE_SPACE 1G\nMIN_FREE_SPACE_RUNNING 256M\n";
+
         fs::write("/etc/atomic-update.conf", config_contents)
             .expect("Unable to write to /etc/atomic-update.conf");
     }
@@ -110,6 +171,36 @@ pub fn create_config_file() {
     }
 }
 
+/// Parse a size such as `2G` or `500M` from a line, in bytes. Suffixes are
+/// powers of 1024, and a plain number is bytes.
+fn parse_size(line: &str) -> Option<u64> {
+    let value = line.split(' ').next_back().unwrap();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&value[..i], 1 << 40),
+        _ => (value, 1),
+    };
+
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(size) => Some(size),
+        None => {
+            eprintln!("Ignoring invalid size {:?} in /etc/atomic-update.conf", value);
+            None
+        }
+    }
+}
+
+/// Parse the number of seconds from a `TIMEOUT_*` line, where 0 means no limit.
+fn parse_seconds(line: &str) -> u64 {
+    let value = line.split(' ').next_back().unwrap();
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Ignoring invalid timeout {:?} in /etc/atomic-update.conf", value);
+        0
+    })
+}
+
 pub fn read_config_file() -> Result<ConfigOpts, std::io::Error> {
     let config_file_path = Path::new("/etc/atomic-update.conf");
 
@@ -127,25 +218,106 @@ pub fn read_config_file() -> Result<ConfigOpts, std::io::Error> {
     let mut yes_flag = "-y";
     let mut root_partition = "";
     let mut root_subvolume = "";
+    let mut exec_backend = "namespace";
+    let mut package_cache = None;
+    let mut download_flag = None;
+    let mut prefetch_packages = false;
+    let mut env_allowlist = None;
+    let mut timeout_update = 0;
+    let mut timeout_install = 0;
+    let mut timeout_exec = 0;
+    let mut cpu_weight = "";
+    let mut memory_max = "";
+    let mut io_weight = "";
+    let mut nice = "";
+    let mut io_class = "";
+    let mut health_checks = None;
+    let mut merge_etc = true;
+    let mut persistent_paths = Vec::new();
+    let mut defer_swap = false;
+    let mut snapshot_space_warn = 0;
+    let mut min_free_space = 0;
+    let mut min_free_space_running = 0;
 
     // must be a more elegant way to do this
     let file_contents = read_to_string(config_file_path).unwrap();
     for line in file_contents.lines() {
         if line.starts_with("UPDATE_COMMAND") {
-            update_command = line.split(' ').last().unwrap();
+            update_command = line.split(' ').next_back().unwrap();
         } else if line.starts_with("PACKAGE_MANAGER") {
-            package_manager = line.split(' ').last().unwrap();
+            package_manager = line.split(' ').next_back().unwrap();
         } else if line.starts_with("INSTALL_COMMAND") {
-            install_command = line.split(' ').last().unwrap();
+            install_command = line.split(' ').next_back().unwrap();
         } else if line.starts_with("YES_FLAG") {
-            yes_flag = line.split(' ').last().unwrap();
+            yes_flag = line.split(' ').next_back().unwrap();
         } else if line.starts_with("ROOT_PARTITION") {
-            root_partition = line.split(' ').last().unwrap();
+            root_partition = line.split(' ').next_back().unwrap();
         } else if line.starts_with("ROOT_SUBVOLUME") {
-            root_subvolume = line.split(' ').last().unwrap();
+            root_subvolume = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("EXEC_BACKEND") {
+            exec_backend = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("PACKAGE_CACHE") {
+            package_cache = line.split(' ').next_back();
+        } else if line.starts_with("DOWNLOAD_FLAG") {
+            download_flag = line.split(' ').next_back();
+        } else if line.starts_with("PREFETCH_PACKAGES") {
+            prefetch_packages = line.split(' ').next_back() == Some("yes");
+        } else if line.starts_with("ENV_ALLOWLIST") {
+            env_allowlist = Some(
+                line.split(' ')
+                    .skip(1)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+                    .collect(),
+            );
+        } else if line.starts_with("TIMEOUT_UPDATE") {
+            timeout_update = parse_seconds(line);
+        } else if line.starts_with("TIMEOUT_INSTALL") {
+            timeout_install = parse_seconds(line);
+        } else if line.starts_with("TIMEOUT_EXEC") {
+            timeout_exec = parse_seconds(line);
+        } else if line.starts_with("CPU_WEIGHT") {
+            cpu_weight = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("MEMORY_MAX") {
+            memory_max = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("IO_WEIGHT") {
+            io_weight = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("NICE") {
+            nice = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("IO_CLASS") {
+            io_class = line.split(' ').next_back().unwrap();
+        } else if line.starts_with("PERSISTENT_PATH") {
+            let setting: Vec<&str> = line.split(' ').skip(1).filter(|v| !v.is_empty()).collect();
+            persistent_paths.push(setting.join(" "));
+        } else if line.starts_with("DEFER_SWAP") {
+            defer_swap = line.split(' ').next_back() == Some("yes");
+        } else if line.starts_with("MIN_FREE_SPACE_RUNNING") {
+            min_free_space_running = parse_size(line).unwrap_or(min_free_space_running);
+        } else if line.starts_with("MIN_FREE_SPACE") {
+            min_free_space = parse_size(line).unwrap_or(min_free_space);
+        } else if line.starts_with("SNAPSHOT_SPACE_WARN") {
+            let value = line.split(' ').next_back().unwrap().trim_end_matches('%');
+            snapshot_space_warn = value.parse().unwrap_or_else(|_| {
+                eprintln!("Ignoring invalid SNAPSHOT_SPACE_WARN {:?} in /etc/atomic-update.conf", value);
+                0
+            });
+        } else if line.starts_with("MERGE_ETC") {
+            merge_etc = line.split(' ').next_back() != Some("no");
+        } else if line.starts_with("HEALTH_CHECKS") {
+            health_checks = Some(
+                line.split(' ')
+                    .skip(1)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+                    .collect(),
+            );
         }
     }
 
+    // The cache is only used when configured, as `init` does for new configs,
+    // so that upgrading doesn't start sharing one behind the user's back
+    let (_, default_download_flag) = package_cache_defaults(package_manager);
+
     let co = ConfigOpts {
         update_command: update_command.to_string(),
         package_manager: package_manager.to_string(),
@@ -153,7 +325,54 @@ pub fn read_config_file() -> Result<ConfigOpts, std::io::Error> {
         yes_flag: yes_flag.to_string(),
         root_partition: root_partition.to_string(),
         root_subvolume: root_subvolume.to_string(),
+        exec_backend: exec_backend.to_string(),
+        package_cache: package_cache.unwrap_or("").to_string(),
+        download_flag: download_flag.unwrap_or(default_download_flag).to_string(),
+        prefetch_packages,
+        env_allowlist: env_allowlist
+            .unwrap_or_else(|| DEFAULT_ENV_ALLOWLIST.iter().map(|v| v.to_string()).collect()),
+        timeout_update,
+        timeout_install,
+        timeout_exec,
+        cpu_weight: cpu_weight.to_string(),
+        memory_max: memory_max.to_string(),
+        io_weight: io_weight.to_string(),
+        nice: nice.to_string(),
+        io_class: io_class.to_string(),
+        health_checks: health_checks
+            .unwrap_or_else(|| BUILTIN_CHECKS.iter().map(|c| c.to_string()).collect()),
+        merge_etc,
+        persistent_paths,
+        defer_swap,
+        snapshot_space_warn,
+        min_free_space,
+        min_free_space_running,
     };
 
     Ok(co)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("MIN_FREE_SPACE 512"), Some(512));
+        assert_eq!(parse_size("MIN_FREE_SPACE 0"), Some(0));
+        assert_eq!(parse_size("MIN_FREE_SPACE 4K"), Some(4 * 1024));
+        assert_eq!(parse_size("MIN_FREE_SPACE 500M"), Some(500 * 1024 * 1024));
+        assert_eq!(parse_size("MIN_FREE_SPACE 2g"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("MIN_FREE_SPACE 1T"), Some(1 << 40));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_values() {
+        assert_eq!(parse_size("MIN_FREE_SPACE"), None);
+        assert_eq!(parse_size("MIN_FREE_SPACE 1.5G"), None);
+        assert_eq!(parse_size("MIN_FREE_SPACE G"), None);
+        assert_eq!(parse_size("MIN_FREE_SPACE 10GB"), None);
+        assert_eq!(parse_size("MIN_FREE_SPACE -1M"), None);
+        assert_eq!(parse_size("MIN_FREE_SPACE 99999999999T"), None);
+    }
+}
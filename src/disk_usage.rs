@@ -0,0 +1,272 @@
+//! Working out how much space snapshots take up.
+//!
+//! With quota groups enabled the kernel keeps count of the bytes each
+//! subvolume references, and how many of those only it references. Without
+//! them the same is estimated by asking for the extents of every file in the
+//! snapshot, which is slow but needs no setup.
+//!
+//! Free space is checked before and while commands run in a snapshot, since a
+//! package manager running out of space mid-transaction leaves it broken.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::btrfs_handler::SnapshotEntry;
+use crate::btrfs_ioctl;
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+}
+
+/// `_IOWR('f', 11, struct fiemap)`
+const FS_IOC_FIEMAP: c_ulong = 0xc020660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// Extents asked for per `FS_IOC_FIEMAP` call.
+const FIEMAP_BATCH: usize = 256;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+#[repr(C)]
+struct StatVfs {
+    f_bsize: c_ulong,
+    f_frsize: c_ulong,
+    f_blocks: u64,
+    f_bfree: u64,
+    f_bavail: u64,
+    f_files: u64,
+    f_ffree: u64,
+    f_favail: u64,
+    f_fsid: c_ulong,
+    f_flag: c_ulong,
+    f_namemax: c_ulong,
+    f_spare: [c_int; 6],
+}
+
+/// Space used by a snapshot.
+#[derive(Default)]
+pub struct Usage {
+    /// Bytes no other snapshot refers to, freed by deleting this one.
+    pub exclusive: u64,
+    /// Bytes shared with other snapshots or the root.
+    pub shared: u64,
+}
+
+/// Add up the extents of the file at `path`.
+fn add_file_extents(path: &Path, usage: &mut Usage) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut map: Box<Fiemap> = Box::new(unsafe { std::mem::zeroed() });
+    let mut start = 0;
+
+    loop {
+        map.start = start;
+        map.length = u64::MAX - start;
+        map.flags = FIEMAP_FLAG_SYNC;
+        map.extent_count = FIEMAP_BATCH as u32;
+        map.mapped_extents = 0;
+
+        let ret = unsafe { ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut *map as *mut Fiemap) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if map.mapped_extents == 0 {
+            return Ok(());
+        }
+
+        for extent in &map.extents[..map.mapped_extents as usize] {
+            if extent.flags & FIEMAP_EXTENT_SHARED != 0 {
+                usage.shared += extent.length;
+            } else {
+                usage.exclusive += extent.length;
+            }
+
+            if extent.flags & FIEMAP_EXTENT_LAST != 0 {
+                return Ok(());
+            }
+            start = extent.logical + extent.length;
+        }
+    }
+}
+
+fn add_dir_extents(dir: &Path, device: u64, usage: &mut Usage) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = path.symlink_metadata()?;
+
+        // Nested subvolumes are accounted on their own
+        if meta.dev() != device || (meta.is_dir() && meta.ino() == 256) {
+            continue;
+        }
+
+        if meta.is_dir() {
+            add_dir_extents(&path, device, usage)?;
+        } else if meta.is_file() {
+            // Files may disappear from the running root while we walk it
+            if let Err(e) = add_file_extents(&path, usage) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Estimate the space used by the subvolume at `path` from the extents of
+/// every file in it.
+pub fn extent_usage(path: &Path) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    let device = path.symlink_metadata()?.dev();
+    add_dir_extents(path, device, &mut usage)?;
+    Ok(usage)
+}
+
+/// The space used by each of `snapshots`, found below `top_level`. Quota
+/// groups are used when enabled; otherwise the extents are walked only if
+/// `walk_extents` is set, and the usage is left unknown if not.
+pub fn snapshot_usage(snapshots: &[SnapshotEntry], top_level: &Path, walk_extents: bool) -> Vec<Option<Usage>> {
+    if btrfs_ioctl::quotas_enabled(top_level) {
+        match btrfs_ioctl::qgroup_usage(top_level) {
+            Ok(qgroups) => {
+                return snapshots
+                    .iter()
+                    .map(|s| {
+                        qgroups.get(&s.subvol_id).map(|q| Usage {
+                            exclusive: q.exclusive,
+                            shared: q.referenced.saturating_sub(q.exclusive),
+                        })
+                    })
+                    .collect();
+            }
+            Err(e) => eprintln!("Could not read quota groups: {}", e),
+        }
+    }
+
+    if !walk_extents {
+        return snapshots.iter().map(|_| None).collect();
+    }
+
+    snapshots
+        .iter()
+        .map(|s| match extent_usage(&top_level.join(&s.path)) {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                eprintln!("Could not work out the space used by snapshot {}: {}", s.id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn filesystem_stats(path: &Path) -> io::Result<StatVfs> {
+    let mut c_path = path.as_os_str().as_bytes().to_vec();
+    c_path.push(0);
+
+    let mut stats: StatVfs = unsafe { std::mem::zeroed() };
+    if unsafe { statvfs(c_path.as_ptr() as *const c_char, &mut stats) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stats)
+}
+
+/// The size in bytes of the filesystem containing `path`.
+pub fn filesystem_size(path: &Path) -> io::Result<u64> {
+    let stats = filesystem_stats(path)?;
+    Ok(stats.f_blocks * stats.f_frsize as u64)
+}
+
+/// Space which can still be written to the filesystem containing `path`.
+/// On btrfs this is worked out from its chunks and devices, counting space
+/// not yet allocated to any chunk; `statvfs` is only used for other
+/// filesystems, or if that fails.
+pub fn free_space(path: &Path) -> io::Result<btrfs_ioctl::FreeSpace> {
+    match btrfs_ioctl::free_space(path) {
+        Ok(free) => Ok(free),
+        Err(_) => {
+            let stats = filesystem_stats(path)?;
+            let available = stats.f_bavail * stats.f_frsize as u64;
+            Ok(btrfs_ioctl::FreeSpace {
+                data: available,
+                metadata: available,
+            })
+        }
+    }
+}
+
+/// Fail with `StorageFull` if less than `minimum` bytes are free for data on
+/// the filesystem containing `path`, or less than an eighth of that for
+/// metadata, which btrfs runs out of separately.
+pub fn ensure_free_space(path: &Path, minimum: u64) -> io::Result<()> {
+    let free = free_space(path)?;
+    let metadata_minimum = minimum / 8;
+
+    let shortage = if free.data < minimum {
+        Some(("", free.data, minimum))
+    } else if free.metadata < metadata_minimum {
+        Some((" for metadata", free.metadata, metadata_minimum))
+    } else {
+        None
+    };
+
+    if let Some((what, available, needed)) = shortage {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "Only {} free{} on the filesystem, less than {}",
+                format_bytes(available),
+                what,
+                format_bytes(needed)
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Format a byte count the way `btrfs` and `df -h` do, e.g. `1.50GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.2}{}", value, UNITS[unit])
+    }
+}
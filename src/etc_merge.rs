@@ -0,0 +1,253 @@
+//! Carrying changes made to /etc on the running system over into a snapshot.
+//!
+//! When a snapshot is taken its /etc is copied to `/.au-etc-base` inside it,
+//! before the command gets to change anything. At swap time every path under
+//! the live /etc is compared with that base and the snapshot's /etc:
+//!
+//! - changed only on the live system: the live version is copied over
+//! - changed in both, text files: merged with `diff3`
+//! - anything else changed in both: the snapshot's version is kept and the live
+//!   one is saved next to it as `<name>.au-live`, and reported as a conflict
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{self as unix_fs, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::utils::run_command;
+
+/// Where the copy of /etc taken with the snapshot is kept, inside it.
+pub const ETC_BASE: &str = ".au-etc-base";
+
+const LIVE_ETC: &str = "/etc";
+
+/// Copy the snapshot's /etc aside, as the base to merge against later. Must be
+/// called before anything changes the snapshot.
+pub fn save_etc_base(snapshot_path: &Path) -> io::Result<()> {
+    let etc = snapshot_path.join("etc");
+    let base = snapshot_path.join(ETC_BASE);
+
+    let output = run_command(
+        String::from("cp"),
+        Some(&[
+            "-a",
+            "--reflink=auto",
+            etc.to_str().unwrap(),
+            base.to_str().unwrap(),
+        ]),
+    )?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Failed copying {:?} to {:?}: {}",
+            etc,
+            base,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Remove the snapshot's base copy of /etc, so that nothing is merged into it.
+pub fn drop_etc_base(snapshot_path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(snapshot_path.join(ETC_BASE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether two paths hold the same thing, without following symlinks.
+fn same_entry(a: &Path, b: &Path) -> bool {
+    let (meta_a, meta_b) = match (a.symlink_metadata(), b.symlink_metadata()) {
+        (Err(_), Err(_)) => return true,
+        (Ok(meta_a), Ok(meta_b)) => (meta_a, meta_b),
+        _ => return false,
+    };
+
+    if meta_a.file_type() != meta_b.file_type()
+        || meta_a.mode() != meta_b.mode()
+        || meta_a.uid() != meta_b.uid()
+        || meta_a.gid() != meta_b.gid()
+    {
+        return false;
+    }
+
+    if meta_a.file_type().is_symlink() {
+        return fs::read_link(a).ok() == fs::read_link(b).ok();
+    }
+    if meta_a.is_file() {
+        return meta_a.len() == meta_b.len() && fs::read(a).ok() == fs::read(b).ok();
+    }
+
+    // Directories are compared entry by entry as the walk reaches them
+    true
+}
+
+fn is_file(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|m| m.is_file())
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Make `target` a copy of `source`, including ownership and permissions. A
+/// directory is created empty, its contents are copied by the walk.
+fn copy_entry(source: &Path, target: &Path) -> io::Result<()> {
+    let meta = source.symlink_metadata()?;
+
+    if meta.is_dir() {
+        if !target.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+            remove_entry(target)?;
+            fs::create_dir(target)?;
+        }
+        fs::set_permissions(target, meta.permissions())?;
+    } else if meta.file_type().is_symlink() {
+        remove_entry(target)?;
+        unix_fs::symlink(fs::read_link(source)?, target)?;
+    } else {
+        if target.symlink_metadata().is_ok_and(|m| !m.is_file()) {
+            remove_entry(target)?;
+        }
+        fs::copy(source, target)?;
+    }
+
+    unix_fs::lchown(target, Some(meta.uid()), Some(meta.gid()))
+}
+
+/// Merge the changes made since `base` in `live` into `new`, keeping `new`'s
+/// ownership and permissions. Returns false if they conflict.
+fn merge_file(new: &Path, base: &Path, live: &Path) -> io::Result<bool> {
+    if !(is_file(new) && is_file(base) && is_file(live)) {
+        return Ok(false);
+    }
+
+    let output = match run_command(
+        String::from("diff3"),
+        Some(&[
+            "-m",
+            new.to_str().unwrap(),
+            base.to_str().unwrap(),
+            live.to_str().unwrap(),
+        ]),
+    ) {
+        Ok(o) => o,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    // diff3 exits 1 when there are conflicts and 2 on binary files
+    if !output.status.success() {
+        return Ok(false);
+    }
+
+    fs::write(new, output.stdout)?;
+    Ok(true)
+}
+
+struct Merge {
+    new_etc: PathBuf,
+    base_etc: PathBuf,
+    conflicts: Vec<String>,
+}
+
+impl Merge {
+    fn merge_path(&mut self, relative: &Path) -> io::Result<()> {
+        let live = Path::new(LIVE_ETC).join(relative);
+        let base = self.base_etc.join(relative);
+        let new = self.new_etc.join(relative);
+        let display = Path::new(LIVE_ETC).join(relative);
+
+        if same_entry(&live, &base) {
+            // Nothing changed on the live system here, but it may have further down
+            if live.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+                self.merge_children(relative)?;
+            }
+            return Ok(());
+        }
+
+        if same_entry(&new, &base) {
+            match live.symlink_metadata() {
+                Ok(_) => {
+                    println!("Carrying over {:?}", display);
+                    copy_entry(&live, &new)?
+                }
+                Err(_) => {
+                    println!("Carrying over removal of {:?}", display);
+                    remove_entry(&new)?
+                }
+            }
+        } else if same_entry(&new, &live) {
+            // The same change was made in both
+        } else if merge_file(&new, &base, &live)? {
+            println!("Merged {:?}", display);
+            return Ok(());
+        } else if live.symlink_metadata().is_ok_and(|m| m.is_dir())
+            && new.symlink_metadata().is_ok_and(|m| m.is_dir())
+        {
+            // Only the directory's own permissions differ, keep the new ones
+        } else {
+            println!("Conflict in {:?}", display);
+            self.conflicts.push(display.to_string_lossy().into_owned());
+            if live.symlink_metadata().is_ok() && !live.is_dir() {
+                let mut saved = new.clone().into_os_string();
+                saved.push(".au-live");
+                copy_entry(&live, Path::new(&saved))?;
+            }
+            return Ok(());
+        }
+
+        if live.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+            self.merge_children(relative)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge_children(&mut self, relative: &Path) -> io::Result<()> {
+        let mut names = Vec::new();
+        for dir in [Path::new(LIVE_ETC).join(relative), self.base_etc.join(relative)] {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries {
+                    names.push(entry?.file_name());
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+
+        for name in names {
+            self.merge_path(&relative.join(name))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bring changes made to the live /etc since the snapshot was taken into the
+/// snapshot, returning the paths which conflicted. Does nothing for snapshots
+/// without a base copy of /etc.
+pub fn merge_live_etc(snapshot_path: &Path) -> io::Result<Vec<String>> {
+    let base_etc = snapshot_path.join(ETC_BASE);
+    if !base_etc.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    println!("Merging changes made to {} since the snapshot was taken", LIVE_ETC);
+    let mut merge = Merge {
+        new_etc: snapshot_path.join("etc"),
+        base_etc: base_etc.clone(),
+        conflicts: Vec::new(),
+    };
+    merge.merge_children(Path::new(""))?;
+
+    fs::remove_dir_all(&base_etc)?;
+
+    Ok(merge.conflicts)
+}
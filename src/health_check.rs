@@ -0,0 +1,283 @@
+//! Checking a snapshot is fit to boot before it is swapped in.
+//!
+//! The built in checks are selected with `HEALTH_CHECKS` in
+//! /etc/atomic-update.conf. Executables in `/etc/atomic-update.d/checks/` are
+//! run as well, and fail the snapshot by exiting non-zero.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::btrfs_handler::Operation;
+use crate::config_handler::{read_config_file, ConfigOpts};
+use crate::hooks::{self, HookStage};
+use crate::utils::run_command;
+
+/// The built in checks, in the order they run.
+pub const BUILTIN_CHECKS: [&str; 3] = ["packages", "kernel", "fstab"];
+
+pub struct CheckFailure {
+    pub check: String,
+    pub reason: String,
+}
+
+impl fmt::Display for CheckFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.check, self.reason)
+    }
+}
+
+/// Run the package manager's own consistency check against the snapshot's
+/// package database.
+fn check_package_database(snapshot_path: &Path, package_manager: &str) -> Result<(), String> {
+    let root = snapshot_path.to_str().unwrap();
+    let (program, args): (&str, Vec<&str>) = match package_manager {
+        "dnf" | "zypper" => ("rpmdb", vec!["--root", root, "--verifydb"]),
+        "apt" => ("dpkg", vec!["--root", root, "--audit"]),
+        "pacman" => ("pacman", vec!["--sysroot", root, "-Dk"]),
+        other => {
+            println!("Skipping package database check, {} is not supported", other);
+            return Ok(());
+        }
+    };
+
+    let output = match run_command(program.to_string(), Some(&args)) {
+        Ok(o) => o,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("Skipping package database check, {} is not installed", program);
+            return Ok(());
+        }
+        Err(e) => return Err(format!("could not run {}: {}", program, e)),
+    };
+
+    // dpkg --audit reports problems on stdout without necessarily failing
+    let complaints = if program == "dpkg" { output.stdout } else { output.stderr };
+    if !output.status.success() || (program == "dpkg" && !complaints.is_empty()) {
+        let detail = String::from_utf8_lossy(&complaints);
+        return Err(format!(
+            "{} reported a broken package database: {}",
+            program,
+            detail.trim()
+        ));
+    }
+
+    Ok(())
+}
+
+fn dir_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Directories outside the snapshot a kernel may be installed in: /boot,
+/// and the EFI system partition for unified kernel images and Boot Loader
+/// Specification entries.
+const BOOT_DIRS: [&str; 3] = ["/boot", "/efi", "/boot/efi"];
+
+/// Whether a kernel matching `version` is installed below `boot_dir`, as
+/// `vmlinuz-<name>` for one of `names`, a Boot Loader Specification entry
+/// `<entry token>/<version>/linux`, or a unified kernel image in
+/// `EFI/Linux` with one of `names` in its file name.
+fn boot_dir_has_kernel(boot_dir: &Path, version: &str, names: &[&str]) -> bool {
+    if names
+        .iter()
+        .any(|name| boot_dir.join(format!("vmlinuz-{}", name)).is_file())
+    {
+        return true;
+    }
+
+    if dir_names(boot_dir)
+        .iter()
+        .any(|token| boot_dir.join(token).join(version).join("linux").is_file())
+    {
+        return true;
+    }
+
+    dir_names(&boot_dir.join("EFI/Linux")).iter().any(|image| {
+        image.to_ascii_lowercase().ends_with(".efi") && names.iter().any(|name| image.contains(name))
+    })
+}
+
+/// Make sure there is a kernel for one of the module trees installed in the
+/// snapshot, so it won't boot a kernel without its modules. Besides /boot and
+/// the EFI system partition, kernels shipped inside the module tree itself
+/// are accepted, as those are copied into place by `kernel-install`.
+fn check_kernel(snapshot_path: &Path) -> Result<(), String> {
+    let modules_dir = ["usr/lib/modules", "lib/modules"]
+        .iter()
+        .map(|dir| snapshot_path.join(dir))
+        .find(|dir| dir.is_dir())
+        .ok_or("the snapshot has no /lib/modules")?;
+
+    let versions = dir_names(&modules_dir);
+    if versions.is_empty() {
+        return Err(format!("{:?} is empty", modules_dir));
+    }
+
+    // A separate /boot partition is not part of the snapshot, only the mount
+    // point is
+    let mut boot_dirs = Vec::new();
+    for dir in BOOT_DIRS {
+        let snapshot_dir = snapshot_path.join(dir.trim_start_matches('/'));
+        if dir_names(&snapshot_dir).is_empty() {
+            boot_dirs.push(PathBuf::from(dir));
+        } else {
+            boot_dirs.push(snapshot_dir);
+        }
+    }
+
+    for version in &versions {
+        if modules_dir.join(version).join("vmlinuz").is_file() {
+            return Ok(());
+        }
+
+        // Arch names its kernels after the package rather than the version
+        let pkgbase = fs::read_to_string(modules_dir.join(version).join("pkgbase"));
+        let mut names = vec![version.as_str()];
+        if let Ok(name) = &pkgbase {
+            names.push(name.trim());
+        }
+
+        if boot_dirs
+            .iter()
+            .any(|dir| boot_dir_has_kernel(dir, version, &names))
+        {
+            return Ok(());
+        }
+    }
+
+    Err(format!(
+        "no kernel in {:?} matches the modules for {}",
+        boot_dirs,
+        versions.join(", ")
+    ))
+}
+
+/// Whether the device an fstab entry refers to exists on this machine.
+fn fstab_device_exists(spec: &str) -> bool {
+    let by_tag = [
+        ("UUID=", "/dev/disk/by-uuid"),
+        ("LABEL=", "/dev/disk/by-label"),
+        ("PARTUUID=", "/dev/disk/by-partuuid"),
+        ("PARTLABEL=", "/dev/disk/by-partlabel"),
+    ];
+
+    for (tag, dir) in by_tag {
+        if let Some(value) = spec.strip_prefix(tag) {
+            return Path::new(dir).join(value.trim_matches('"')).exists();
+        }
+    }
+
+    // Pseudo filesystems such as tmpfs or proc have no device to look for
+    !spec.starts_with('/') || Path::new(spec).exists()
+}
+
+/// Look for entries in the snapshot's /etc/fstab which would stop it booting.
+fn check_fstab(snapshot_path: &Path) -> Result<(), String> {
+    let contents = match fs::read_to_string(snapshot_path.join("etc/fstab")) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("could not read /etc/fstab: {}", e)),
+    };
+
+    let mut problems = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+
+        let number = number + 1;
+        if fields.len() < 4 {
+            problems.push(format!("line {} has fewer than 4 fields", number));
+            continue;
+        }
+
+        let (spec, mount_point, options) = (fields[0], fields[1], fields[3]);
+        if !mount_point.starts_with('/') && mount_point != "none" && mount_point != "swap" {
+            problems.push(format!("line {} has invalid mount point {:?}", number, mount_point));
+        }
+
+        let optional = options.split(',').any(|o| o == "nofail" || o == "noauto");
+        if !optional && !fstab_device_exists(spec) {
+            problems.push(format!("line {} refers to missing device {:?}", number, spec));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("/etc/fstab: {}", problems.join(", ")))
+    }
+}
+
+fn run_builtin_check(
+    check: &str,
+    snapshot_path: &Path,
+    opts: Option<&ConfigOpts>,
+) -> Result<(), String> {
+    match check {
+        "packages" => match opts {
+            Some(opts) => check_package_database(snapshot_path, &opts.package_manager),
+            None => Ok(()),
+        },
+        "kernel" => check_kernel(snapshot_path),
+        "fstab" => check_fstab(snapshot_path),
+        other => {
+            eprintln!("Ignoring unknown health check {:?} in HEALTH_CHECKS", other);
+            Ok(())
+        }
+    }
+}
+
+/// Run every enabled check against the snapshot, returning those which failed.
+pub fn run_health_checks(snapshot_path: &Path, operation: Operation) -> Vec<CheckFailure> {
+    let config = read_config_file().ok();
+    let checks = match config.as_ref() {
+        Some(opts) => opts.health_checks.clone(),
+        None => BUILTIN_CHECKS.iter().map(|c| c.to_string()).collect(),
+    };
+
+    let mut failures = Vec::new();
+
+    for check in &checks {
+        println!("Checking {}", check);
+        if let Err(reason) = run_builtin_check(check, snapshot_path, config.as_ref()) {
+            failures.push(CheckFailure {
+                check: check.clone(),
+                reason,
+            });
+        }
+    }
+
+    let scripts = match hooks::find_hooks(HookStage::Check) {
+        Ok(s) => s,
+        Err(e) => {
+            failures.push(CheckFailure {
+                check: String::from("scripts"),
+                reason: format!("could not list check scripts: {}", e),
+            });
+            Vec::new()
+        }
+    };
+
+    for script in scripts {
+        if let Err(e) = hooks::run_hook(HookStage::Check, operation, snapshot_path, &script) {
+            let name = script.file_name().unwrap().to_string_lossy().into_owned();
+            failures.push(CheckFailure {
+                check: name,
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    failures
+}
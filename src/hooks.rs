@@ -0,0 +1,121 @@
+//! Site specific scripts run at fixed points of an operation.
+//!
+//! Every executable file in `/etc/atomic-update.d/<stage>/` is run in name
+//! order, on the running system, with these variables set:
+//!
+//! - `AU_OPERATION`: `update`, `install`, `exec` or `promote`, which only runs
+//!   the `pre-swap` and `post-swap` hooks
+//! - `AU_HOOK_STAGE`: the stage being run, e.g. `pre-swap`
+//! - `AU_SNAPSHOT_PATH`: the snapshot the operation works on. For `post-swap`
+//!   hooks this is the new root, which is only mounted while they run.
+//!
+//! A hook exiting non-zero stops the operation at that point, except for
+//! `post-swap` hooks, which run once there is nothing left to stop. Scripts in
+//! `checks/` are health checks, see `health_check`.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::btrfs_handler::Operation;
+
+const HOOKS_DIR: &str = "/etc/atomic-update.d";
+
+#[derive(Clone, Copy)]
+pub enum HookStage {
+    /// Before the root is snapshotted.
+    PreSnapshot,
+    /// After the command succeeded, while /proc, /sys and /dev are still
+    /// mounted in the snapshot.
+    InSnapshot,
+    /// Before the snapshot is swapped in as the next root.
+    PreSwap,
+    /// After the swap.
+    PostSwap,
+    /// Health checks, run after `InSnapshot` hooks and before `PreSwap`.
+    Check,
+}
+
+impl HookStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookStage::PreSnapshot => "pre-snapshot",
+            HookStage::InSnapshot => "in-snapshot",
+            HookStage::PreSwap => "pre-swap",
+            HookStage::PostSwap => "post-swap",
+            HookStage::Check => "checks",
+        }
+    }
+}
+
+/// The hooks for `stage`, in the order they run. Hidden files, editor backups
+/// and anything not executable are skipped.
+pub fn find_hooks(stage: HookStage) -> io::Result<Vec<PathBuf>> {
+    let dir = Path::new(HOOKS_DIR).join(stage.name());
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut hooks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with('.') || name.ends_with('~') {
+            continue;
+        }
+
+        // A dangling symlink shouldn't keep every other hook from running
+        let meta = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Skipping hook {:?}: {}", path, e);
+                continue;
+            }
+        };
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        }
+    }
+    hooks.sort();
+
+    Ok(hooks)
+}
+
+/// Run a single hook found by `find_hooks`.
+pub fn run_hook(
+    stage: HookStage,
+    operation: Operation,
+    snapshot_path: &Path,
+    hook: &Path,
+) -> io::Result<()> {
+    println!("Running {} hook {:?}", stage.name(), hook);
+
+    let status = Command::new(hook)
+        .env("AU_OPERATION", operation.name())
+        .env("AU_HOOK_STAGE", stage.name())
+        .env("AU_SNAPSHOT_PATH", snapshot_path)
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed running hook {:?}: {}", hook, e)))?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} hook {:?} exited with {}",
+            stage.name(),
+            hook,
+            status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Run every hook for `stage`, stopping at the first that fails.
+pub fn run_hooks(stage: HookStage, operation: Operation, snapshot_path: &Path) -> io::Result<()> {
+    for hook in find_hooks(stage)? {
+        run_hook(stage, operation, snapshot_path, &hook)?;
+    }
+
+    Ok(())
+}
@@ -1,24 +1,60 @@
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::{env, io, process};
 
 use btrfs_handler::*;
 
-use crate::config_handler::{create_config_file, read_config_file};
-use crate::utils::get_root_partition_device;
+use crate::config_handler::{create_config_file, read_config_file, ConfigOpts};
+use crate::hooks::{run_hooks, HookStage};
+use crate::snapshot_metadata::{SnapshotMetadata, SnapshotState};
+use crate::utils::{format_timestamp, get_root_partition_device};
 
 mod btrfs_handler;
+mod btrfs_ioctl;
 mod config_handler;
+mod disk_usage;
+mod etc_merge;
+mod health_check;
+mod hooks;
+mod mount;
+mod package_cache;
+mod package_query;
+mod persistent_paths;
+mod process_handler;
+mod resolv_conf;
+mod resource_limits;
+mod sandbox;
+mod signal_handler;
+mod snapshot_diff;
+mod snapshot_id;
+mod snapshot_metadata;
+mod snapshot_transfer;
+mod staged_swap;
 mod utils;
 
 fn usage() {
     println!("Usage:");
     println!("au init - Initialise a system with atomic-update.");
-    println!("au update - Update your system in a new snapshot.");
-    println!("au exec [command arg1 arg2] - Run a command in a new snapshot. e.g. atomic-update exec dnf install sshfs -y");
-    println!("au install [pkg1 pkg2] - Install a package into a new snapshot");
+    println!("au update [--no-swap] [--message text] - Update your system in a new snapshot.");
+    println!("au exec [--no-swap] [--message text] [command arg1 arg2] - Run a command in a new snapshot. e.g. atomic-update exec dnf install sshfs -y");
+    println!("au install [--no-swap] [--message text] [pkg1 pkg2] - Install a package into a new snapshot");
+    println!("    --no-swap - Leave the snapshot in /.au-snapshots instead of booting it next.");
+    println!("    --message [text] - Describe the change, shown by list.");
     println!("au rollback - Undo last operation.");
+    println!("au promote [id] - Make a copy of snapshot [id] the root from the next boot.");
+    println!("au list [--usage] - List the snapshots kept in /.au-snapshots.");
+    println!("    --usage - Work out the space used by each snapshot when quotas are not enabled, which is slow.");
+    println!("au delete [id] - Delete snapshot [id].");
+    println!("au pin [id] [note] - Keep snapshot [id] from being deleted until unpinned.");
+    println!("au unpin [id] - Allow snapshot [id] to be deleted again.");
+    println!("au tag [id] [name] - Let snapshot [id] be referred to as [name] wherever an id is accepted.");
+    println!("au untag [id] [name] - Remove tag [name] from snapshot [id].");
+    println!("au diff [id] [other id] [--prefix /path] - List files changed between two snapshots, or a snapshot and the running root.");
+    println!("au package-diff [id] [other id] - List packages changed between two snapshots, or a snapshot and the running root.");
+    println!("au export [id] [file] [--parent id] - Write snapshot [id] to [file], only with its changes from the parent if given.");
+    println!("au import [file] - Add the snapshot exported to [file] to /.au-snapshots.");
+    println!("au discard - Cancel a change which has not been booted into yet.");
 }
 
 fn init() {
@@ -49,21 +85,344 @@ fn init() {
     create_snapshots_dir();
 
     create_config_file();
+
+    if let Ok(opts) = read_config_file() {
+        if let Err(e) = persistent_paths::create_persistent_subvolumes(&opts) {
+            eprintln!("Failed setting up persistent paths: {}", e);
+            exit(1);
+        }
+    }
+
+    offer_quotas();
+}
+
+/// Ask whether to enable quota groups, so `list` can show the space used by
+/// each snapshot without walking through all their files.
+fn offer_quotas() {
+    if btrfs_ioctl::quotas_enabled(Path::new("/")) {
+        return;
+    }
+
+    print!("Enable btrfs quotas to track the space used by each snapshot? This slows down deleting snapshots on large filesystems [y/N]: ");
+    io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return;
+    }
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        return;
+    }
+
+    match btrfs_ioctl::enable_quotas(Path::new("/")) {
+        Ok(()) => println!("Quotas enabled, usage will be shown once the kernel has finished scanning the filesystem"),
+        Err(e) => eprintln!("Could not enable quotas: {}", e),
+    }
+}
+
+/// Options accepted by update, install and exec before their arguments.
+struct RunOptions {
+    /// Whether to swap the snapshot in once it is ready.
+    swap: bool,
+    /// Recorded in the snapshot's metadata.
+    message: Option<String>,
+}
+
+/// Split leading options off the arguments of update, install and exec.
+fn parse_run_options(args: &[String]) -> (RunOptions, Vec<String>) {
+    let mut options = RunOptions {
+        swap: true,
+        message: None,
+    };
+
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        match arg.as_str() {
+            "--no-swap" => options.swap = false,
+            "--message" if rest.len() > 1 => {
+                // Metadata holds one value per line
+                options.message = Some(rest[1].replace('\n', " "));
+                rest = &rest[1..];
+            }
+            _ => match arg.strip_prefix("--message=") {
+                Some(message) => options.message = Some(message.replace('\n', " ")),
+                None => break,
+            },
+        }
+        rest = &rest[1..];
+    }
+
+    (options, rest.to_vec())
+}
+
+/// Download packages on the running system first when PREFETCH_PACKAGES is
+/// set, which keeps the time spent in the snapshot short.
+fn prefetch_if_enabled(opts: &ConfigOpts, args: &[&str]) {
+    if !opts.prefetch_packages || !package_cache::is_enabled(opts) {
+        return;
+    }
+
+    if let Err(e) = package_cache::prefetch_packages(opts, args) {
+        eprintln!("Could not download packages ahead of time, continuing: {}", e);
+    }
+}
+
+/// Make snapshots read-only which nothing should change anymore.
+fn archive_old_snapshots() {
+    if let Err(e) = archive_snapshots() {
+        eprintln!("Could not make old snapshots read-only: {}", e);
+    }
+}
+
+/// Stop if a signal arrived, discarding the snapshot being prepared.
+fn abort_if_interrupted(snapshot_path: &Path) {
+    if let Some(signum) = signal_handler::pending() {
+        eprintln!("Interrupted, discarding {:?}", snapshot_path);
+        if let Err(e) = discard_snapshot(snapshot_path) {
+            eprintln!("Failed to discard {:?}, please delete it manually: {}", snapshot_path, e);
+        }
+        exit(signal_handler::exit_code(signum));
+    }
+}
+
+/// Signals are ignored while subvolumes are being swapped, exit now that it's done.
+fn exit_if_interrupted_during_swap() {
+    if let Some(signum) = signal_handler::pending() {
+        eprintln!("Interrupted during the swap, which was allowed to finish");
+        exit(signal_handler::exit_code(signum));
+    }
+}
+
+/// Copy the `sync` persistent paths into the root filesystem at `target_root`,
+/// which is about to become the root. Without them the swap would lose data,
+/// so it is not attempted if they can't be copied.
+fn sync_persistent_paths_into(target_root: &Path) {
+    if let Ok(opts) = read_config_file() {
+        if let Err(e) = persistent_paths::sync_persistent_paths(&opts, target_root) {
+            eprintln!(
+                "Not swapping, failed carrying persistent paths over into {:?}: {}",
+                target_root, e
+            );
+            exit(1);
+        }
+    }
+}
+
+/// Carry changes made to /etc since the snapshot was taken over into it,
+/// recording any conflicts in its metadata.
+fn merge_live_etc_into(snapshot_path: &Path, metadata: &mut SnapshotMetadata) {
+    match etc_merge::merge_live_etc(snapshot_path) {
+        Ok(conflicts) if !conflicts.is_empty() => {
+            eprintln!(
+                "These files were changed both on the running system and in the snapshot, the snapshot's versions are kept and the running system's saved as <file>.au-live:"
+            );
+            for path in &conflicts {
+                eprintln!("    {}", path);
+            }
+            metadata.etc_conflicts = conflicts;
+            metadata.save(snapshot_path);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed merging changes made to /etc, some may not be carried over: {}", e),
+    }
 }
 
-fn update() {
+/// Make the prepared snapshot the root from the next boot, carrying over what
+/// changed on the running system since it was taken.
+fn swap_in(snapshot_path: &Path, metadata: &mut SnapshotMetadata) {
+    sync_persistent_paths_into(snapshot_path);
+    merge_live_etc_into(snapshot_path, metadata);
+    swap_snapshot_to_root(snapshot_path);
+    println!("Success, changes will take effect at next reboot!");
+
+    // The snapshot has been moved into place as the next root, so the hooks
+    // are given that instead, with the top level mounted while they run
+    let root_subvol_name = match get_root_subvolume_name() {
+        Some(name) => name,
+        None => return,
+    };
+    let post_swap = with_top_level(|top_level| {
+        run_hooks(HookStage::PostSwap, metadata.operation, &top_level.join(root_subvol_name))
+    });
+    if let Err(e) = post_swap {
+        eprintln!("{}", e);
+    }
+}
+
+/// Swap in the snapshot now, or at shutdown with `DEFER_SWAP`.
+fn swap_in_or_stage(snapshot_path: &Path, metadata: &mut SnapshotMetadata) {
+    if read_config_file().is_ok_and(|opts| opts.defer_swap) {
+        match staged_swap::stage(snapshot_path) {
+            Ok(()) => {
+                metadata.set_state(snapshot_path, SnapshotState::Staged);
+                println!("Success, the snapshot will be swapped in when the system shuts down!");
+                return;
+            }
+            Err(e) => eprintln!("Could not defer the swap to shutdown, swapping now: {}", e),
+        }
+    }
+
+    swap_in(snapshot_path, metadata);
+    exit_if_interrupted_during_swap();
+}
+
+/// Swap in the snapshot staged by `DEFER_SWAP`, run at shutdown by
+/// atomic-update-swap.service.
+fn apply_staged() {
+    signal_handler::install();
+
+    let snapshot_path = match staged_swap::staged_snapshot() {
+        Ok(Some(path)) => path,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Not swapping, could not read the staged swap: {}", e);
+            let _ = staged_swap::clear();
+            exit(1);
+        }
+    };
+
+    if !snapshot_path.is_dir() {
+        eprintln!("The staged snapshot {:?} no longer exists", snapshot_path);
+        let _ = staged_swap::clear();
+        exit(1);
+    }
+
+    let mut metadata = match SnapshotMetadata::read(&snapshot_path) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Not swapping, could not read the staged snapshot's metadata: {}", e);
+            exit(1);
+        }
+    };
+
+    println!("Swapping in staged snapshot {:?}", snapshot_path);
+    if let Err(e) = staged_swap::clear() {
+        eprintln!("Could not clear the staged swap: {}", e);
+    }
+    metadata.set_state(&snapshot_path, SnapshotState::Ready);
+    swap_in(&snapshot_path, &mut metadata);
+}
+
+/// Snapshot the root, run `command` in the snapshot and, if it succeeds, make
+/// the snapshot the root filesystem from the next boot.
+fn run_in_new_snapshot(
+    operation: Operation,
+    command: String,
+    args: &[&str],
+    options: &RunOptions,
+) {
+    // Interrupted while packages were being downloaded
+    if let Some(signum) = signal_handler::pending() {
+        exit(signal_handler::exit_code(signum));
+    }
+
+    archive_old_snapshots();
+
     let next_snapshot_location = get_next_snapshot_path().expect("Could not parse snapshot dir");
     let next_snapshot_path = Path::new(next_snapshot_location.as_str());
+
+    if let Err(e) = run_hooks(HookStage::PreSnapshot, operation, next_snapshot_path) {
+        eprintln!("Aborting, {}", e);
+        exit(1);
+    }
+
+    let min_free_space = read_config_file().map_or(0, |opts| opts.min_free_space);
+    if min_free_space > 0 {
+        if let Err(e) = disk_usage::ensure_free_space(Path::new("/"), min_free_space) {
+            eprintln!("Not starting, {}. Free up space, e.g. by deleting old snapshots", e);
+            exit(1);
+        }
+    }
+
     create_root_snapshot(next_snapshot_path).expect("Could not create snapshot");
+    let mut metadata = SnapshotMetadata::new(operation);
+    metadata.identify(next_snapshot_path);
+    metadata.message = options.message.clone();
+    metadata.set_state(next_snapshot_path, SnapshotState::Building);
+    if read_config_file().map_or(true, |opts| opts.merge_etc) {
+        if let Err(e) = etc_merge::save_etc_base(next_snapshot_path) {
+            eprintln!("Changes made to /etc from now on will not be carried over: {}", e);
+        }
+    }
+    abort_if_interrupted(next_snapshot_path);
+
+    match run_command_in_snapshot_chroot(next_snapshot_path, operation, command, Some(args)) {
+        Ok(()) => {
+            println!("Success!");
+            abort_if_interrupted(next_snapshot_path);
+
+            let failures = health_check::run_health_checks(next_snapshot_path, operation);
+            abort_if_interrupted(next_snapshot_path);
+            if !failures.is_empty() {
+                for failure in &failures {
+                    eprintln!("Health check failed: {}", failure);
+                }
+                metadata.failed_checks = failures.iter().map(|f| f.to_string()).collect();
+                metadata.set_state(next_snapshot_path, SnapshotState::CheckFailed);
+                archive_snapshot(next_snapshot_path);
+                eprintln!(
+                    "Not swapping, the snapshot has been left at {:?} for inspection",
+                    next_snapshot_path
+                );
+                exit(1);
+            }
+            if !options.swap {
+                metadata.set_state(next_snapshot_path, SnapshotState::Prepared);
+                archive_snapshot(next_snapshot_path);
+                println!(
+                    "Success, the snapshot has been prepared at {:?}. Run 'atomic-update promote {}' to boot it",
+                    next_snapshot_path,
+                    next_snapshot_path.file_name().unwrap().to_string_lossy()
+                );
+                return;
+            }
+            metadata.set_state(next_snapshot_path, SnapshotState::Ready);
+
+            if let Err(e) = run_hooks(HookStage::PreSwap, operation, next_snapshot_path) {
+                // The snapshot itself is fine, so it is kept for promoting
+                metadata.set_state(next_snapshot_path, SnapshotState::Prepared);
+                archive_snapshot(next_snapshot_path);
+                eprintln!(
+                    "Not swapping, {}. The snapshot has been left at {:?}",
+                    e, next_snapshot_path
+                );
+                exit(1);
+            }
+
+            swap_in_or_stage(next_snapshot_path, &mut metadata);
+        }
+        Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+            // Whatever the command left half done is of no use, and deleting
+            // it gives the space back
+            eprintln!("Failed: {}. Discarding {:?}", e, next_snapshot_path);
+            if let Err(e) = discard_snapshot(next_snapshot_path) {
+                eprintln!("Failed to discard {:?}, please delete it manually: {}", next_snapshot_path, e);
+            }
+            exit(1);
+        }
+        Err(e) => {
+            println!("Failed: {:?}", e);
+            abort_if_interrupted(next_snapshot_path);
+            metadata.set_state(next_snapshot_path, SnapshotState::Failed);
+            archive_snapshot(next_snapshot_path);
+            exit(1);
+        }
+    }
+}
+
+fn update(options: &RunOptions) {
+    signal_handler::install();
+
+    let config = read_config_file();
 
     let mut package_manager = String::from("");
     let mut update_command = String::from("");
     let mut yes_flag = String::from("");
 
-    if let Ok(opts) = read_config_file() {
-        package_manager = opts.package_manager;
-        update_command = opts.update_command;
-        yes_flag = opts.yes_flag;
+    if let Ok(opts) = &config {
+        package_manager = opts.package_manager.clone();
+        update_command = opts.update_command.clone();
+        yes_flag = opts.yes_flag.clone();
     }
 
     if package_manager.is_empty() || update_command.is_empty() || yes_flag.is_empty() {
@@ -71,34 +430,28 @@ fn update() {
         exit(1);
     }
 
-    match run_command_in_snapshot_chroot(
-        next_snapshot_path,
-        package_manager,
-        Some(vec![update_command.as_str(), yes_flag.as_str()].as_slice()),
-    ) {
-        Ok(()) => {
-            println!("Success!");
-            swap_snapshot_to_root(next_snapshot_path);
-        }
-        Err(e) => {
-            println!("Failed: {:?}", e);
-        }
+    let update_args = vec![update_command.as_str(), yes_flag.as_str()];
+
+    if let Ok(opts) = &config {
+        prefetch_if_enabled(opts, &update_args);
     }
+
+    run_in_new_snapshot(Operation::Update, package_manager, &update_args, options);
 }
 
-fn install(cmd_args: &mut [String]) {
-    let next_snapshot_location = get_next_snapshot_path().expect("Could not parse snapshot dir");
-    let next_snapshot_path = Path::new(next_snapshot_location.as_str());
-    create_root_snapshot(next_snapshot_path).expect("Could not create snapshot");
+fn install(cmd_args: &mut [String], options: &RunOptions) {
+    signal_handler::install();
+
+    let config = read_config_file();
 
     let mut package_manager = String::from("");
     let mut install_command = String::from("");
     let mut yes_flag = String::from("");
 
-    if let Ok(opts) = read_config_file() {
-        package_manager = opts.package_manager;
-        install_command = opts.install_command;
-        yes_flag = opts.yes_flag;
+    if let Ok(opts) = &config {
+        package_manager = opts.package_manager.clone();
+        install_command = opts.install_command.clone();
+        yes_flag = opts.yes_flag.clone();
     }
 
     if package_manager.is_empty() || install_command.is_empty() || yes_flag.is_empty() {
@@ -114,65 +467,635 @@ fn install(cmd_args: &mut [String]) {
 
     println!("{:?}", install_cmd);
 
-    match run_command_in_snapshot_chroot(
-        next_snapshot_path,
-        package_manager,
-        Some(install_cmd.as_slice()),
-    ) {
-        Ok(()) => {
-            println!("Success!");
-            swap_snapshot_to_root(next_snapshot_path);
+    if let Ok(opts) = &config {
+        prefetch_if_enabled(opts, &install_cmd);
+    }
+
+    run_in_new_snapshot(Operation::Install, package_manager, &install_cmd, options);
+}
+
+fn exec_cmd(cmd_args: &mut [String], options: &RunOptions) {
+    signal_handler::install();
+
+    let cmd_to_run = cmd_args[0].clone();
+    let args_to_run: Vec<&str> = cmd_args[1..].iter().map(|s| s.as_str()).collect();
+
+    run_in_new_snapshot(Operation::Exec, cmd_to_run, &args_to_run, options);
+}
+
+fn rollback() {
+    signal_handler::install();
+
+    println!(
+        "Swapping rollback and {}",
+        get_root_subvolume_name().unwrap()
+    );
+    if let Ok(Some(staged)) = staged_swap::staged_snapshot() {
+        println!("Cancelling the swap of {:?} staged for shutdown", staged);
+        if let Err(e) = staged_swap::clear() {
+            eprintln!("Could not cancel the staged swap: {}", e);
+            exit(1);
+        }
+        staged_swap::unstage(&staged);
+    }
+
+    // Persistent paths are carried over once the swap has made the slot
+    // writable
+    swap_rollback_to_root(|rollback_path| match read_config_file() {
+        Ok(opts) => persistent_paths::sync_persistent_paths(&opts, rollback_path),
+        Err(_) => Ok(()),
+    });
+    println!("Success, changes will take effect at next reboot!");
+
+    exit_if_interrupted_during_swap();
+}
+
+/// Make a copy of retained snapshot `id` the root from the next boot, with the
+/// current root becoming the rollback, as after an update.
+fn promote(id: &str) {
+    signal_handler::install();
+
+    if !is_root_user() {
+        eprintln!("promote must be run as root!");
+        exit(1);
+    }
+
+    match next_boot_root_is_running() {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("Another root is already waiting for the next boot, reboot or run 'atomic-update discard' first");
+            exit(1);
         }
         Err(e) => {
-            println!("Failed: {:?}", e);
+            eprintln!("Could not determine the root booted next: {}", e);
+            exit(1);
         }
     }
-}
 
-fn exec_cmd(cmd_args: &mut Vec<String>) {
+    archive_old_snapshots();
+
+    let snapshot = match find_snapshot(id) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
     let next_snapshot_location = get_next_snapshot_path().expect("Could not parse snapshot dir");
     let next_snapshot_path = Path::new(next_snapshot_location.as_str());
-    create_root_snapshot(next_snapshot_path).expect("Could not create snapshot");
 
-    let cmd_to_run = cmd_args[0].clone();
+    println!("Promoting snapshot {} ({})", snapshot.id, snapshot.path);
+    if let Err(e) = copy_snapshot(&snapshot.path, next_snapshot_path) {
+        eprintln!("Error creating snapshot: {}", e);
+        exit(1);
+    }
 
-    if cmd_args.len() == 1 {
-        match run_command_in_snapshot_chroot(next_snapshot_path, cmd_to_run.clone(), None) {
-            Ok(()) => {
-                println!("Worked!");
-                swap_snapshot_to_root(next_snapshot_path);
+    let mut metadata = SnapshotMetadata::read(next_snapshot_path)
+        .unwrap_or_else(|_| SnapshotMetadata::new(Operation::Promote));
+    if matches!(
+        metadata.state,
+        SnapshotState::Building | SnapshotState::Failed | SnapshotState::CheckFailed
+    ) {
+        println!(
+            "Warning: snapshot {} is marked {}, promoting it anyway",
+            snapshot.id,
+            metadata.state.name()
+        );
+    }
+    if let Err(e) = drop_etc_base_unless_of_running_root(&snapshot.path, next_snapshot_path) {
+        eprintln!("Not promoting, could not remove the snapshot's copy of /etc: {}", e);
+        if let Err(e) = discard_snapshot(next_snapshot_path) {
+            eprintln!("Failed to discard {:?}, please delete it manually: {}", next_snapshot_path, e);
+        }
+        exit(1);
+    }
+    metadata.identify(next_snapshot_path);
+    metadata.operation = Operation::Promote;
+    metadata.promoted_from = Some(snapshot.id.clone());
+    // Pins and tags stay with the original
+    metadata.pinned = None;
+    metadata.tags.clear();
+    metadata.set_state(next_snapshot_path, SnapshotState::Ready);
+    abort_if_interrupted(next_snapshot_path);
+
+    if let Err(e) = run_hooks(HookStage::PreSwap, Operation::Promote, next_snapshot_path) {
+        eprintln!("Not promoting, {}", e);
+        if let Err(e) = discard_snapshot(next_snapshot_path) {
+            eprintln!("Failed to discard {:?}, please delete it manually: {}", next_snapshot_path, e);
+        }
+        exit(1);
+    }
+
+    match keep_running_root() {
+        Ok(kept) => println!("The current root is kept as snapshot {}", kept.display()),
+        Err(e) => {
+            eprintln!("Not promoting, could not keep the current root: {}", e);
+            if let Err(e) = discard_snapshot(next_snapshot_path) {
+                eprintln!("Failed to discard {:?}, please delete it manually: {}", next_snapshot_path, e);
             }
-            Err(e) => {
-                println!("nope, {:?}", e);
+            exit(1);
+        }
+    }
+
+    swap_in_or_stage(next_snapshot_path, &mut metadata);
+}
+
+/// Live /etc changes are only merged into a promoted snapshot taken of the
+/// running root. Older snapshots predate the changes made since, including
+/// those of later package updates, and merging them in would undo going back
+/// to the older system, so the copy's base is dropped instead.
+fn drop_etc_base_unless_of_running_root(source_path: &str, copy_path: &Path) -> io::Result<()> {
+    let root_uuid = btrfs_ioctl::subvolume_info(Path::new("/"))?.uuid;
+    let source_parent_uuid =
+        with_top_level(|top_level| Ok(btrfs_ioctl::subvolume_info(&top_level.join(source_path))?.parent_uuid))?;
+
+    if source_parent_uuid == root_uuid {
+        return Ok(());
+    }
+    etc_merge::drop_etc_base(copy_path)
+}
+
+/// Take a read-only snapshot of the running root under a new number before
+/// promoting another, so that it stays available to promote back after later
+/// swaps have taken over the rollback slot.
+fn keep_running_root() -> io::Result<PathBuf> {
+    let path = PathBuf::from(get_next_snapshot_path()?);
+    create_root_snapshot(&path)?;
+
+    let mut metadata = SnapshotMetadata::read(&path)
+        .unwrap_or_else(|_| SnapshotMetadata::new(Operation::Promote));
+    metadata.identify(&path);
+    // Pins and tags stay with whichever snapshot the root was made from
+    metadata.pinned = None;
+    metadata.tags.clear();
+    metadata.set_state(&path, SnapshotState::Prepared);
+    archive_snapshot(&path);
+
+    Ok(path)
+}
+
+fn list(args: &[String]) {
+    if !is_root_user() {
+        eprintln!("list must be run as root!");
+        exit(1);
+    }
+
+    let walk_extents = args.iter().any(|a| a == "--usage");
+    let space_warn = read_config_file().map_or(0, |opts| opts.snapshot_space_warn);
+
+    let listed = with_top_level(|top_level| {
+        let snapshots = list_snapshots()?;
+        let usage = disk_usage::snapshot_usage(&snapshots, top_level, walk_extents);
+
+        println!(
+            "{:<10} {:<16} {:<14} {:<10} {:<4} {:>10} {:>10} PATH",
+            "ID", "CREATED", "STATE", "OPERATION", "MODE", "EXCLUSIVE", "SHARED"
+        );
+        for (snapshot, usage) in snapshots.iter().zip(&usage) {
+            let path = top_level.join(&snapshot.path);
+            let metadata = SnapshotMetadata::read(&path);
+            let created = match metadata.as_ref().ok().and_then(|m| m.created) {
+                Some(created) => Some(created),
+                None => btrfs_ioctl::subvolume_info(&path).ok().map(|info| info.created),
+            };
+            let created = created.map_or(String::from("-"), format_timestamp);
+            let mode = match btrfs_ioctl::is_readonly(&path) {
+                Ok(true) => "ro",
+                Ok(false) => "rw",
+                Err(_) => "-",
+            };
+            let (state, operation) = match &metadata {
+                Ok(metadata) => (metadata.state.name(), metadata.operation.name()),
+                Err(_) => ("-", "-"),
+            };
+            let (exclusive, shared) = match usage {
+                Some(u) => (disk_usage::format_bytes(u.exclusive), disk_usage::format_bytes(u.shared)),
+                None => (String::from("-"), String::from("-")),
+            };
+            // Rollback slots are shown by the number they were created under
+            let id = match metadata.as_ref().ok().and_then(|m| m.id) {
+                Some(id) => id.to_string(),
+                None => snapshot.id.clone(),
+            };
+            println!(
+                "{:<10} {:<16} {:<14} {:<10} {:<4} {:>10} {:>10} {}",
+                id, created, state, operation, mode, exclusive, shared, snapshot.path
+            );
+
+            if let Ok(metadata) = &metadata {
+                if let Some(note) = &metadata.pinned {
+                    match note.as_str() {
+                        "" => println!("    PINNED"),
+                        note => println!("    PINNED: {}", note),
+                    }
+                }
+                if !metadata.tags.is_empty() {
+                    println!("    tags: {}", metadata.tags.join(", "));
+                }
+                if let Some(message) = &metadata.message {
+                    println!("    message: {}", message);
+                }
             }
         }
-    } else {
-        let args_to_run: Vec<&str> = cmd_args[1..].iter().map(|s| s.as_str()).collect();
 
-        match run_command_in_snapshot_chroot(
-            next_snapshot_path,
-            cmd_to_run.clone(),
-            Some(&args_to_run),
-        ) {
-            Ok(()) => {
-                println!("Worked!");
-                swap_snapshot_to_root(next_snapshot_path);
-                println!("Success, changes will take effect at next reboot!")
+        if space_warn > 0 && usage.iter().any(|u| u.is_some()) {
+            let total: u64 = usage.iter().flatten().map(|u| u.exclusive).sum();
+            let size = disk_usage::filesystem_size(top_level)?;
+            if total * 100 > size * space_warn {
+                eprintln!(
+                    "Warning: snapshots hold {} of the {} filesystem, more than SNAPSHOT_SPACE_WARN {}%. Consider deleting some",
+                    disk_usage::format_bytes(total),
+                    disk_usage::format_bytes(size),
+                    space_warn
+                );
             }
-            Err(e) => {
-                println!("nope, {:?}", e);
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = listed {
+        eprintln!("Could not list snapshots: {}", e);
+        exit(1);
+    }
+}
+
+fn delete(id: &str) {
+    if !is_root_user() {
+        eprintln!("delete must be run as root!");
+        exit(1);
+    }
+
+    let snapshot = match find_snapshot(id) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let pinned = with_top_level(|top_level| {
+        Ok(SnapshotMetadata::is_pinned(&top_level.join(&snapshot.path)))
+    });
+    if pinned.unwrap_or(false) {
+        eprintln!(
+            "Snapshot {} is pinned, run 'atomic-update unpin {}' first to delete it",
+            snapshot.id, snapshot.id
+        );
+        exit(1);
+    }
+
+    // A snapshot staged for shutdown is always in the running root, not nested
+    // in a rollback slot
+    if let Ok(Some(staged)) = staged_swap::staged_snapshot() {
+        let in_running_root = snapshot.path.matches(".au-snapshots").count() == 1;
+        if in_running_root && staged.file_name().is_some_and(|name| name == snapshot.id.as_str()) {
+            println!("Cancelling the swap of {:?} staged for shutdown", staged);
+            if let Err(e) = staged_swap::clear() {
+                eprintln!("Could not cancel the staged swap: {}", e);
+                exit(1);
             }
         }
     }
+
+    match delete_snapshot(&snapshot.path) {
+        Ok(()) => println!("Deleted snapshot {} ({})", snapshot.id, snapshot.path),
+        Err(e) => {
+            eprintln!("Failed to delete snapshot {}: {}", snapshot.id, e);
+            exit(1);
+        }
+    }
 }
 
-fn rollback() {
-    println!(
-        "Swapping rollback and {}",
-        get_root_subvolume_name().unwrap()
-    );
-    swap_rollback_to_root();
-    println!("Success, changes will take effect at next reboot!")
+/// Change the metadata of the snapshot called `id` on the command line.
+fn edit_metadata(id: &str, edit: impl FnOnce(&SnapshotEntry, &mut SnapshotMetadata) -> io::Result<()>) {
+    if !is_root_user() {
+        eprintln!("Changing snapshots must be done as root!");
+        exit(1);
+    }
+
+    let snapshot = match find_snapshot(id) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let edited = with_top_level(|top_level| {
+        let path = top_level.join(&snapshot.path);
+        with_writable(&path, || {
+            let mut metadata = SnapshotMetadata::read(&path)?;
+            edit(&snapshot, &mut metadata)?;
+            metadata.write(&path)
+        })
+    });
+
+    if let Err(e) = edited {
+        eprintln!("Failed changing snapshot {}: {}", snapshot.id, e);
+        exit(1);
+    }
+}
+
+fn pin(id: &str, note: Option<&String>) {
+    edit_metadata(id, |snapshot, metadata| {
+        metadata.pinned = Some(note.map_or(String::new(), |n| n.replace('\n', " ")));
+        println!("Pinned snapshot {}", snapshot.id);
+        Ok(())
+    });
+}
+
+fn unpin(id: &str) {
+    edit_metadata(id, |snapshot, metadata| {
+        metadata.pinned = None;
+        println!("Unpinned snapshot {}", snapshot.id);
+        Ok(())
+    });
+}
+
+fn tag(id: &str, name: &str) {
+    // Tags are looked up only when no snapshot has the number
+    if name.is_empty()
+        || name == "rollback"
+        || name.chars().all(|c| c.is_ascii_digit())
+        || name.contains(char::is_whitespace)
+    {
+        eprintln!("{:?} can't be used as a tag, it must not be a number, 'rollback' or contain spaces", name);
+        exit(1);
+    }
+
+    match find_snapshot(name) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Ok(other) => {
+            eprintln!("Snapshot {} is already tagged {}", other.id, name);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+
+    edit_metadata(id, |snapshot, metadata| {
+        metadata.tags.push(name.to_string());
+        println!("Tagged snapshot {} as {}", snapshot.id, name);
+        Ok(())
+    });
+}
+
+fn untag(id: &str, name: &str) {
+    edit_metadata(id, |snapshot, metadata| {
+        if !metadata.tags.iter().any(|t| t == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("it is not tagged {}", name),
+            ));
+        }
+        metadata.tags.retain(|t| t != name);
+        println!("Removed tag {} from snapshot {}", name, snapshot.id);
+        Ok(())
+    });
+}
+
+/// Where to find the snapshot called `name` on the command line: a number, or
+/// `rollback` for the rollback slot.
+fn snapshot_path_in_top_level(top_level: &Path, name: &str) -> io::Result<PathBuf> {
+    if name == "rollback" {
+        let root_subvol_name = get_root_subvolume_name()
+            .expect("Could not determine root subvolume name - expecting 'root' or '@'");
+        return Ok(top_level.join(root_subvol_name).join(".au-snapshots/rollback"));
+    }
+
+    Ok(top_level.join(find_snapshot(name)?.path))
+}
+
+fn diff(args: &[String]) {
+    // So the temporary snapshots are deleted if this is interrupted
+    signal_handler::install();
+
+    if !is_root_user() {
+        eprintln!("diff must be run as root!");
+        exit(1);
+    }
+
+    let mut prefix = None;
+    let mut snapshots = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--prefix" {
+            prefix = args.next().map(PathBuf::from);
+        } else {
+            snapshots.push(arg.as_str());
+        }
+    }
+
+    if snapshots.is_empty() || snapshots.len() > 2 {
+        println!("diff takes one or two snapshots! \n");
+        return usage();
+    }
+
+    let changes = with_top_level(|top_level| {
+        let from = snapshot_path_in_top_level(top_level, snapshots[0])?;
+        let to = match snapshots.get(1) {
+            Some(name) => snapshot_path_in_top_level(top_level, name)?,
+            None => PathBuf::from("/"),
+        };
+
+        snapshot_diff::diff_subvolumes(&from, &to, top_level)
+    });
+
+    if let Some(signum) = signal_handler::pending() {
+        exit(signal_handler::exit_code(signum));
+    }
+
+    let changes = match changes {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Could not compare snapshots: {}", e);
+            exit(1);
+        }
+    };
+
+    for (path, change) in changes {
+        if prefix.as_ref().is_some_and(|p| !Path::new(&path).starts_with(p)) {
+            continue;
+        }
+        println!("{:<9} {}", change, path);
+    }
+}
+
+fn package_diff(args: &[String]) {
+    if !is_root_user() {
+        eprintln!("package-diff must be run as root!");
+        exit(1);
+    }
+
+    if args.is_empty() || args.len() > 2 {
+        println!("package-diff takes one or two snapshots! \n");
+        return usage();
+    }
+
+    let package_manager = match read_config_file() {
+        Ok(opts) => opts.package_manager,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let changes = with_top_level(|top_level| {
+        let from = snapshot_path_in_top_level(top_level, &args[0])?;
+        let to = match args.get(1) {
+            Some(name) => snapshot_path_in_top_level(top_level, name)?,
+            None => PathBuf::from("/"),
+        };
+
+        let from_packages = package_query::installed_packages(&from, &package_manager)?;
+        let to_packages = package_query::installed_packages(&to, &package_manager)?;
+        Ok(package_query::diff_packages(
+            &package_manager,
+            &from_packages,
+            &to_packages,
+        ))
+    });
+
+    match changes {
+        Ok(changes) => {
+            for (name, change) in changes {
+                println!("{:<10} {} {}", change.kind(), name, change);
+            }
+        }
+        Err(e) => {
+            eprintln!("Could not compare packages: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn export(args: &[String]) {
+    if !is_root_user() {
+        eprintln!("export must be run as root!");
+        exit(1);
+    }
+
+    let mut parent = None;
+    let mut positional = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--parent" => match rest.next() {
+                Some(id) => parent = Some(id),
+                None => {
+                    println!("--parent needs a snapshot! \n");
+                    return usage();
+                }
+            },
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        println!("export takes a snapshot and a file! \n");
+        return usage();
+    }
+
+    let find = |id: &str| match find_snapshot(id) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let snapshot = find(positional[0]);
+    let parent = parent.map(|id| find(id));
+    let file = Path::new(positional[1]);
+
+    println!("Exporting snapshot {} to {:?}", snapshot.id, file);
+    let exported = with_top_level(|top_level| {
+        let parent_path = parent.as_ref().map(|p| top_level.join(&p.path));
+        snapshot_transfer::export_snapshot(&top_level.join(&snapshot.path), parent_path.as_deref(), file)
+    });
+
+    if let Err(e) = exported {
+        eprintln!("Failed to export snapshot {}: {}", snapshot.id, e);
+        exit(1);
+    }
+}
+
+fn import(file: &str) {
+    if !is_root_user() {
+        eprintln!("import must be run as root!");
+        exit(1);
+    }
+
+    create_snapshots_dir();
+
+    let imported = with_top_level(|top_level| {
+        snapshot_transfer::import_snapshot(Path::new(file), Path::new("/.au-snapshots"), top_level)
+    });
+
+    match imported {
+        Ok(path) => {
+            println!(
+                "Imported as snapshot {}",
+                path.file_name().unwrap().to_string_lossy()
+            );
+            match SnapshotMetadata::read(&path) {
+                Ok(metadata) => {
+                    if let Some(message) = metadata.message {
+                        println!("    message: {}", message);
+                    }
+                }
+                Err(_) => eprintln!(
+                    "Warning: the snapshot has no atomic-update metadata, it may not have been exported by atomic-update"
+                ),
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to import {}: {}", file, e);
+            exit(1);
+        }
+    }
+}
+
+fn discard() {
+    signal_handler::install();
+
+    if !is_root_user() {
+        eprintln!("discard must be run as root!");
+        exit(1);
+    }
+
+    let mut discarded_anything = false;
+
+    if let Ok(Some(staged)) = staged_swap::staged_snapshot() {
+        println!("Cancelling the swap of {:?} staged for shutdown", staged);
+        if let Err(e) = staged_swap::clear() {
+            eprintln!("Could not cancel the staged swap: {}", e);
+            exit(1);
+        }
+        if let Err(e) = discard_snapshot(&staged) {
+            eprintln!("Failed to discard {:?}, please delete it manually: {}", staged, e);
+        }
+        discarded_anything = true;
+    }
+
+    match discard_pending_root() {
+        Ok(true) => discarded_anything = true,
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Failed to discard the pending root: {}", e);
+            exit(1);
+        }
+    }
+
+    if discarded_anything {
+        println!("Success, the running root will be booted again!");
+    } else {
+        println!("Nothing to discard, the running root is already the one booted next");
+    }
+
+    exit_if_interrupted_during_swap();
 }
 
 fn deb() {
@@ -188,31 +1111,137 @@ fn main() {
 
     match args[1].as_str() {
         "init" => init(),
-        "update" => update(),
+        "update" => {
+            let (options, rest) = parse_run_options(&args[2..]);
+            if let Some(arg) = rest.first() {
+                println!("Unknown option {} passed to update! \n", arg);
+                return usage();
+            }
+            update(&options);
+        }
         "exec" => {
-            if args.len() < 3 {
+            let (options, mut cmd_args) = parse_run_options(&args[2..]);
+            if cmd_args.is_empty() {
                 println!("Not enough args passed to exec! \n");
                 return usage();
             }
-            let mut cmd_args = args[2..args.len()].to_vec();
-            exec_cmd(&mut cmd_args);
+            exec_cmd(&mut cmd_args, &options);
         }
         "install" => {
-            if args.len() < 3 {
+            let (options, mut cmd_args) = parse_run_options(&args[2..]);
+            if cmd_args.is_empty() {
                 println!("Not enough args passed to install! \n");
                 return usage();
             }
-            let mut cmd_args = args[2..args.len()].to_vec();
-            return install(&mut cmd_args);
+            install(&mut cmd_args, &options);
+        }
+        "rollback" => rollback(),
+        "promote" => {
+            if args.len() < 3 {
+                println!("No snapshot passed to promote! \n");
+                return usage();
+            }
+            promote(&args[2]);
+        }
+        "list" => list(&args[2..]),
+        "delete" => {
+            if args.len() < 3 {
+                println!("No snapshot passed to delete! \n");
+                return usage();
+            }
+            delete(&args[2]);
+        }
+        "pin" => {
+            if args.len() < 3 {
+                println!("No snapshot passed to pin! \n");
+                return usage();
+            }
+            pin(&args[2], args.get(3));
         }
-        "rollback" => {
-            return rollback();
+        "unpin" => {
+            if args.len() < 3 {
+                println!("No snapshot passed to unpin! \n");
+                return usage();
+            }
+            unpin(&args[2]);
         }
-        "deb" => {
-            return deb();
+        "tag" | "untag" => {
+            if args.len() < 4 {
+                println!("{} needs a snapshot and a tag! \n", args[1]);
+                return usage();
+            }
+            if args[1] == "tag" {
+                tag(&args[2], &args[3]);
+            } else {
+                untag(&args[2], &args[3]);
+            }
         }
-        _ => {
-            return usage();
+        "diff" => diff(&args[2..]),
+        "package-diff" => package_diff(&args[2..]),
+        "export" => export(&args[2..]),
+        "import" => {
+            if args.len() < 3 {
+                println!("No file passed to import! \n");
+                return usage();
+            }
+            import(&args[2]);
         }
+        "discard" => discard(),
+        "apply-staged" => apply_staged(),
+        "deb" => deb(),
+        _ => usage(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn run_options_defaults() {
+        let (options, rest) = parse_run_options(&args(&["vim", "-y"]));
+        assert!(options.swap);
+        assert_eq!(options.message, None);
+        assert_eq!(rest, args(&["vim", "-y"]));
+    }
+
+    #[test]
+    fn run_options_before_the_command() {
+        let (options, rest) = parse_run_options(&args(&["--no-swap", "--message", "try vim", "vim"]));
+        assert!(!options.swap);
+        assert_eq!(options.message.as_deref(), Some("try vim"));
+        assert_eq!(rest, args(&["vim"]));
+
+        let (options, rest) = parse_run_options(&args(&["--message=try vim", "--no-swap"]));
+        assert!(!options.swap);
+        assert_eq!(options.message.as_deref(), Some("try vim"));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn run_options_stop_at_the_command() {
+        // Options after the command belong to it
+        let (options, rest) = parse_run_options(&args(&["dnf", "--no-swap", "--message", "x"]));
+        assert!(options.swap);
+        assert_eq!(options.message, None);
+        assert_eq!(rest, args(&["dnf", "--no-swap", "--message", "x"]));
+    }
+
+    #[test]
+    fn run_options_message_is_one_line() {
+        let (options, _) = parse_run_options(&args(&["--message", "first\nsecond"]));
+        assert_eq!(options.message.as_deref(), Some("first second"));
+    }
+
+    #[test]
+    fn run_options_message_without_value() {
+        // A trailing --message is left for the command rather than swallowed
+        let (options, rest) = parse_run_options(&args(&["--message"]));
+        assert_eq!(options.message, None);
+        assert_eq!(rest, args(&["--message"]));
     }
 }
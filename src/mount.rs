@@ -0,0 +1,150 @@
+//! Mounting through the `mount(2)` and `umount2(2)` syscalls.
+//!
+//! Every successful mount hands back a `MountGuard`, which unmounts the target
+//! again when dropped, so early returns and panics never leave stray mounts.
+
+use std::ffi::CString;
+use std::io;
+use std::ops::BitOr;
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+extern "C" {
+    fn mount(
+        source: *const c_char,
+        target: *const c_char,
+        filesystemtype: *const c_char,
+        mountflags: c_ulong,
+        data: *const c_void,
+    ) -> c_int;
+    fn umount2(target: *const c_char, flags: c_int) -> c_int;
+}
+
+const MNT_DETACH: c_int = 2;
+
+/// Flags passed as `mountflags` to `mount(2)`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MountFlags(c_ulong);
+
+impl MountFlags {
+    pub const NONE: MountFlags = MountFlags(0);
+    pub const BIND: MountFlags = MountFlags(4096);
+}
+
+impl BitOr for MountFlags {
+    type Output = MountFlags;
+
+    fn bitor(self, rhs: MountFlags) -> MountFlags {
+        MountFlags(self.0 | rhs.0)
+    }
+}
+
+/// A mount made by atomic-update, unmounted when this goes out of scope.
+pub struct MountGuard {
+    target: PathBuf,
+}
+
+impl MountGuard {
+    /// Leave the filesystem mounted for good, rather than when this is dropped.
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if let Err(e) = unmount(&self.target) {
+            eprintln!(
+                "Failed unmounting {:?}, please do this manually with 'sudo umount {}': {}",
+                self.target,
+                self.target.display(),
+                e
+            );
+        }
+    }
+}
+
+fn path_to_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Path {:?} contains a NUL byte", path),
+        )
+    })
+}
+
+fn str_to_cstring(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Mount argument {:?} contains a NUL byte", s),
+        )
+    })
+}
+
+/// Mount `source` of type `fstype` on `target`, with optional filesystem
+/// specific `data` such as `subvolid=5`.
+pub fn mount_filesystem(
+    source: &Path,
+    target: &Path,
+    fstype: &str,
+    flags: MountFlags,
+    data: Option<&str>,
+) -> io::Result<MountGuard> {
+    let source_c = path_to_cstring(source)?;
+    let target_c = path_to_cstring(target)?;
+    let fstype_c = str_to_cstring(fstype)?;
+    let data_c = data.map(str_to_cstring).transpose()?;
+
+    let ret = unsafe {
+        mount(
+            source_c.as_ptr(),
+            target_c.as_ptr(),
+            fstype_c.as_ptr(),
+            flags.0,
+            data_c
+                .as_ref()
+                .map_or(ptr::null(), |d| d.as_ptr() as *const c_void),
+        )
+    };
+    if ret < 0 {
+        let os_error = io::Error::last_os_error();
+        return Err(io::Error::new(
+            os_error.kind(),
+            format!("Failed mounting {:?} on {:?}: {}", source, target, os_error),
+        ));
+    }
+
+    Ok(MountGuard {
+        target: target.to_path_buf(),
+    })
+}
+
+/// Bind mount `source` onto `target`, the equivalent of `mount --bind`.
+pub fn bind_mount(source: &Path, target: &Path) -> io::Result<MountGuard> {
+    mount_filesystem(source, target, "none", MountFlags::BIND, None)
+}
+
+/// Unmount `target`, falling back to a lazy unmount if it is still busy.
+fn unmount(target: &Path) -> io::Result<()> {
+    let target_c = path_to_cstring(target)?;
+
+    if unsafe { umount2(target_c.as_ptr(), 0) } == 0 {
+        return Ok(());
+    }
+
+    let os_error = io::Error::last_os_error();
+    if os_error.raw_os_error() != Some(16) {
+        // Anything other than EBUSY will not be fixed by detaching
+        return Err(os_error);
+    }
+
+    if unsafe { umount2(target_c.as_ptr(), MNT_DETACH) } == 0 {
+        eprintln!("{:?} was busy, it has been lazily unmounted", target);
+        return Ok(());
+    }
+
+    Err(io::Error::last_os_error())
+}
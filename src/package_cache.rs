@@ -0,0 +1,129 @@
+//! Sharing one package cache between the running system and every snapshot.
+//!
+//! The cache lives in its own subvolume at the top level of the filesystem, so
+//! it is never captured by a root snapshot and survives swaps and rollbacks.
+//! It is bind mounted over the package manager's cache directory whenever the
+//! package manager runs.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::btrfs_handler::{mount_top_level, TOP_LEVEL_MOUNT_POINT};
+use crate::btrfs_ioctl;
+use crate::config_handler::ConfigOpts;
+use crate::mount::{self, MountGuard};
+use crate::utils::{get_root_partition_device, run_command};
+
+/// Name of the subvolume holding the shared cache, at the top level.
+const CACHE_SUBVOLUME: &str = "au-package-cache";
+
+/// pacman's database on the running system. Prefetching refreshes the sync
+/// databases, and doing that here without upgrading would leave the running
+/// system partly upgraded, so a copy is used instead.
+const PACMAN_DB: &str = "/var/lib/pacman";
+const PACMAN_PREFETCH_DB: &str = "/run/atomic-update/pacman-db";
+
+/// Whether a shared cache is configured for this package manager.
+pub fn is_enabled(opts: &ConfigOpts) -> bool {
+    !opts.package_cache.is_empty() && opts.package_cache != "none"
+}
+
+/// Bind mount the shared cache for the configured package manager over its
+/// cache directory inside `root`, creating the cache on first use.
+pub fn bind_shared_cache(opts: &ConfigOpts, root: &Path) -> io::Result<MountGuard> {
+    let target = root.join(opts.package_cache.trim_start_matches('/'));
+    fs::create_dir_all(&target)?;
+
+    let root_partition_device = get_root_partition_device();
+    if root_partition_device.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Failed to detect root partition device, please set ROOT_PARTITION in /etc/atomic-update.conf",
+        ));
+    }
+
+    let top_level_path = Path::new(TOP_LEVEL_MOUNT_POINT);
+    fs::create_dir_all(top_level_path)?;
+    let top_level = mount_top_level(&root_partition_device, top_level_path)?;
+
+    let cache_subvolume = top_level_path.join(CACHE_SUBVOLUME);
+    if !cache_subvolume.exists() {
+        println!("Creating shared package cache subvolume {}", CACHE_SUBVOLUME);
+        btrfs_ioctl::create_subvolume(&cache_subvolume)?;
+    }
+
+    let cache_dir: PathBuf = cache_subvolume.join(&opts.package_manager);
+    fs::create_dir_all(&cache_dir)?;
+
+    // The bind mount keeps working once the top level is unmounted again
+    let cache_mount = mount::bind_mount(&cache_dir, &target)?;
+    drop(top_level);
+
+    Ok(cache_mount)
+}
+
+/// Download the packages `args` would install into the shared cache on the
+/// running system, so the package manager in the snapshot only has to install.
+pub fn prefetch_packages(opts: &ConfigOpts, args: &[&str]) -> io::Result<()> {
+    if opts.download_flag.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No DOWNLOAD_FLAG is configured for this package manager",
+        ));
+    }
+
+    let _cache_mount = bind_shared_cache(opts, Path::new("/"))?;
+
+    let mut command = Command::new(&opts.package_manager);
+    command.args(args).arg(&opts.download_flag);
+    if opts.package_manager == "pacman" {
+        copy_pacman_db()?;
+        command.args(["--dbpath", PACMAN_PREFETCH_DB]);
+    }
+
+    println!("Downloading packages before creating the snapshot");
+    let status = command.status();
+    if opts.package_manager == "pacman" {
+        let _ = fs::remove_dir_all(PACMAN_PREFETCH_DB);
+    }
+    let status = status?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            opts.package_manager, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copy pacman's database for prefetching, so that refreshing it leaves the
+/// running system's alone.
+fn copy_pacman_db() -> io::Result<()> {
+    let _ = fs::remove_dir_all(PACMAN_PREFETCH_DB);
+    fs::create_dir_all(PACMAN_PREFETCH_DB)?;
+
+    for dir in ["local", "sync"] {
+        let source = Path::new(PACMAN_DB).join(dir);
+        if !source.is_dir() {
+            continue;
+        }
+
+        let output = run_command(
+            String::from("cp"),
+            Some(&["-a", "--reflink=auto", source.to_str().unwrap(), PACMAN_PREFETCH_DB]),
+        )?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Failed copying {:?}: {}",
+                source,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+    }
+
+    Ok(())
+}
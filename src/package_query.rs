@@ -0,0 +1,492 @@
+//! Reading the installed package set of any root filesystem, such as a
+//! snapshot, without booting it.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::utils::run_command;
+
+/// Installed packages by name. Some packages, like kernels, can be installed
+/// in several versions at once.
+pub type PackageSet = BTreeMap<String, Vec<String>>;
+
+/// Read the packages installed in the root filesystem at `root`.
+pub fn installed_packages(root: &Path, package_manager: &str) -> io::Result<PackageSet> {
+    match package_manager {
+        "dnf" | "zypper" => rpm_packages(root),
+        "apt" => dpkg_packages(root),
+        "pacman" => pacman_packages(root),
+        other => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Reading packages is not supported for {}", other),
+        )),
+    }
+}
+
+fn insert_package(packages: &mut PackageSet, name: &str, version: &str) {
+    packages
+        .entry(name.to_string())
+        .or_default()
+        .push(version.to_string());
+}
+
+fn rpm_packages(root: &Path) -> io::Result<PackageSet> {
+    let output = run_command(
+        String::from("rpm"),
+        Some(&[
+            "--root",
+            root.to_str().unwrap(),
+            "-qa",
+            "--qf",
+            "%{NAME} %{EPOCHNUM}:%{VERSION}-%{RELEASE}\\n",
+        ]),
+    )?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "rpm failed reading packages in {:?}: {}",
+            root,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let mut packages = PackageSet::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((name, version)) = line.split_once(' ') {
+            // Leave out the epoch when it's the default, as rpm does
+            insert_package(&mut packages, name, version.strip_prefix("0:").unwrap_or(version));
+        }
+    }
+
+    Ok(packages)
+}
+
+fn dpkg_packages(root: &Path) -> io::Result<PackageSet> {
+    let status = fs::read_to_string(root.join("var/lib/dpkg/status"))?;
+
+    let mut packages = PackageSet::new();
+    for stanza in status.split("\n\n") {
+        let mut name = None;
+        let mut version = None;
+        let mut installed = false;
+
+        for line in stanza.lines() {
+            if let Some(value) = line.strip_prefix("Package: ") {
+                name = Some(value);
+            } else if let Some(value) = line.strip_prefix("Version: ") {
+                version = Some(value);
+            } else if let Some(value) = line.strip_prefix("Status: ") {
+                installed = value.ends_with(" installed");
+            }
+        }
+
+        if let (Some(name), Some(version), true) = (name, version, installed) {
+            insert_package(&mut packages, name, version);
+        }
+    }
+
+    Ok(packages)
+}
+
+fn pacman_packages(root: &Path) -> io::Result<PackageSet> {
+    let mut packages = PackageSet::new();
+
+    for entry in fs::read_dir(root.join("var/lib/pacman/local"))? {
+        let desc = match fs::read_to_string(entry?.path().join("desc")) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        // Sections are a %HEADER% line followed by values, one per line
+        let mut lines = desc.lines();
+        let mut name = None;
+        let mut version = None;
+        while let Some(line) = lines.next() {
+            match line {
+                "%NAME%" => name = lines.next(),
+                "%VERSION%" => version = lines.next(),
+                _ => {}
+            }
+        }
+
+        if let (Some(name), Some(version)) = (name, version) {
+            insert_package(&mut packages, name, version);
+        }
+    }
+
+    Ok(packages)
+}
+
+/// Split off a leading `epoch:`, which defaults to 0.
+fn split_epoch(version: &str) -> (u64, &str) {
+    match version.split_once(':') {
+        Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => {
+            (epoch.parse().unwrap_or(0), rest)
+        }
+        _ => (0, version),
+    }
+}
+
+/// Compare two runs of digits by value, ignoring leading zeros.
+fn compare_digits(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// rpm's `rpmvercmp`, which pacman uses as well: versions are compared in
+/// alternating runs of digits and letters, where digits are newer than
+/// letters, `~` sorts before anything and `^` after the end of a version.
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+
+    loop {
+        a = a.trim_start_matches(|c: char| !c.is_ascii_alphanumeric() && c != '~' && c != '^');
+        b = b.trim_start_matches(|c: char| !c.is_ascii_alphanumeric() && c != '~' && c != '^');
+
+        match (a.strip_prefix('~'), b.strip_prefix('~')) {
+            (Some(rest_a), Some(rest_b)) => {
+                a = rest_a;
+                b = rest_b;
+                continue;
+            }
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => {}
+        }
+
+        match (a.strip_prefix('^'), b.strip_prefix('^')) {
+            (Some(rest_a), Some(rest_b)) => {
+                a = rest_a;
+                b = rest_b;
+                continue;
+            }
+            (Some(_), None) if b.is_empty() => return Ordering::Greater,
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) if a.is_empty() => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => {}
+        }
+
+        if a.is_empty() || b.is_empty() {
+            return a.len().cmp(&b.len());
+        }
+
+        let numeric = a.starts_with(|c: char| c.is_ascii_digit());
+        let in_segment = |c: char| {
+            if numeric {
+                c.is_ascii_digit()
+            } else {
+                c.is_ascii_alphabetic()
+            }
+        };
+        let end_a = a.find(|c| !in_segment(c)).unwrap_or(a.len());
+        let end_b = b.find(|c| !in_segment(c)).unwrap_or(b.len());
+        let (segment_a, segment_b) = (&a[..end_a], &b[..end_b]);
+
+        // Segments of different types: numbers are newer
+        if segment_b.is_empty() {
+            return if numeric { Ordering::Greater } else { Ordering::Less };
+        }
+
+        let order = if numeric {
+            compare_digits(segment_a, segment_b)
+        } else {
+            segment_a.cmp(segment_b)
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+
+        a = &a[end_a..];
+        b = &b[end_b..];
+    }
+}
+
+/// Compare `[epoch:]version[-release]` strings the way rpm and pacman do.
+fn compare_rpm_versions(a: &str, b: &str) -> Ordering {
+    let (epoch_a, rest_a) = split_epoch(a);
+    let (epoch_b, rest_b) = split_epoch(b);
+    let (version_a, release_a) = rest_a.rsplit_once('-').unwrap_or((rest_a, ""));
+    let (version_b, release_b) = rest_b.rsplit_once('-').unwrap_or((rest_b, ""));
+
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| rpmvercmp(version_a, version_b))
+        .then_with(|| rpmvercmp(release_a, release_b))
+}
+
+/// The sort weight of a character in dpkg's comparison of non-digit parts.
+fn dpkg_order(c: Option<char>) -> i32 {
+    match c {
+        None => 0,
+        Some('~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// dpkg's `verrevcmp`, comparing alternating non-digit and digit parts.
+fn dpkg_verrevcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let order_a = dpkg_order(a.get(i).map(|&c| c as char));
+            let order_b = dpkg_order(b.get(j).map(|&c| c as char));
+            if order_a != order_b {
+                return order_a.cmp(&order_b);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        let start_a = i;
+        while i < a.len() && a[i].is_ascii_digit() {
+            i += 1;
+        }
+        let start_b = j;
+        while j < b.len() && b[j].is_ascii_digit() {
+            j += 1;
+        }
+
+        let digits_a = std::str::from_utf8(&a[start_a..i]).unwrap();
+        let digits_b = std::str::from_utf8(&b[start_b..j]).unwrap();
+        let order = compare_digits(digits_a, digits_b);
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Compare `[epoch:]upstream[-revision]` strings the way dpkg does.
+fn compare_dpkg_versions(a: &str, b: &str) -> Ordering {
+    let (epoch_a, rest_a) = split_epoch(a);
+    let (epoch_b, rest_b) = split_epoch(b);
+    let (upstream_a, revision_a) = rest_a.rsplit_once('-').unwrap_or((rest_a, ""));
+    let (upstream_b, revision_b) = rest_b.rsplit_once('-').unwrap_or((rest_b, ""));
+
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| dpkg_verrevcmp(upstream_a, upstream_b))
+        .then_with(|| dpkg_verrevcmp(revision_a, revision_b))
+}
+
+/// Compare two versions using the package manager's rules.
+pub fn compare_versions(package_manager: &str, a: &str, b: &str) -> Ordering {
+    match package_manager {
+        "apt" => compare_dpkg_versions(a, b),
+        _ => compare_rpm_versions(a, b),
+    }
+}
+
+pub enum PackageChange {
+    Added(Vec<String>),
+    Removed(Vec<String>),
+    Upgraded(Vec<String>, Vec<String>),
+    Downgraded(Vec<String>, Vec<String>),
+}
+
+impl PackageChange {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PackageChange::Added(_) => "added",
+            PackageChange::Removed(_) => "removed",
+            PackageChange::Upgraded(..) => "upgraded",
+            PackageChange::Downgraded(..) => "downgraded",
+        }
+    }
+}
+
+/// The versions involved, e.g. `1.0-1 -> 1.1-1`.
+impl fmt::Display for PackageChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackageChange::Added(versions) | PackageChange::Removed(versions) => {
+                write!(f, "{}", versions.join(", "))
+            }
+            PackageChange::Upgraded(from, to) | PackageChange::Downgraded(from, to) => {
+                write!(f, "{} -> {}", from.join(", "), to.join(", "))
+            }
+        }
+    }
+}
+
+/// Compare two package sets, returning each package which differs.
+pub fn diff_packages(
+    package_manager: &str,
+    from: &PackageSet,
+    to: &PackageSet,
+) -> Vec<(String, PackageChange)> {
+    let newest = |versions: &[String]| {
+        versions
+            .iter()
+            .max_by(|a, b| compare_versions(package_manager, a, b))
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let mut changes = Vec::new();
+
+    for (name, from_versions) in from {
+        let change = match to.get(name) {
+            None => PackageChange::Removed(from_versions.clone()),
+            Some(to_versions) => {
+                let mut sorted_from = from_versions.clone();
+                let mut sorted_to = to_versions.clone();
+                sorted_from.sort();
+                sorted_to.sort();
+                if sorted_from == sorted_to {
+                    continue;
+                }
+
+                match compare_versions(package_manager, &newest(from_versions), &newest(to_versions)) {
+                    Ordering::Greater => PackageChange::Downgraded(sorted_from, sorted_to),
+                    _ => PackageChange::Upgraded(sorted_from, sorted_to),
+                }
+            }
+        };
+        changes.push((name.clone(), change));
+    }
+
+    for (name, to_versions) in to {
+        if !from.contains_key(name) {
+            changes.push((name.clone(), PackageChange::Added(to_versions.clone())));
+        }
+    }
+
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packages(entries: &[(&str, &[&str])]) -> PackageSet {
+        let mut set = PackageSet::new();
+        for (name, versions) in entries {
+            for version in *versions {
+                insert_package(&mut set, name, version);
+            }
+        }
+        set
+    }
+
+    #[test]
+    fn rpmvercmp_segments() {
+        assert_eq!(rpmvercmp("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0", "1.0.0"), Ordering::Less);
+        assert_eq!(rpmvercmp("2.10", "2.9"), Ordering::Greater);
+        assert_eq!(rpmvercmp("2_0", "2.0"), Ordering::Equal);
+        // Letters are older than digits
+        assert_eq!(rpmvercmp("1.0a", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.a", "1.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0a", "1.0b"), Ordering::Less);
+    }
+
+    #[test]
+    fn rpmvercmp_leading_zeros() {
+        assert_eq!(rpmvercmp("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.001", "1.01"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.010", "1.9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn rpmvercmp_tilde_and_caret() {
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0~rc1"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0~~", "1.0~"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0^git2"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1^git1", "1.0~rc1"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1~pre", "1.0^git1"), Ordering::Less);
+    }
+
+    #[test]
+    fn rpm_epochs_and_releases() {
+        assert_eq!(compare_rpm_versions("1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(compare_rpm_versions("0:1.0-1", "1.0-1"), Ordering::Equal);
+        assert_eq!(compare_rpm_versions("1.0-2.fc40", "1.0-10.fc40"), Ordering::Less);
+        assert_eq!(compare_rpm_versions("1.1-1", "1.0-9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn dpkg_versions() {
+        assert_eq!(compare_dpkg_versions("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(compare_dpkg_versions("1.001", "1.1"), Ordering::Equal);
+        assert_eq!(compare_dpkg_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_dpkg_versions("1.0a", "1.0"), Ordering::Greater);
+        assert_eq!(compare_dpkg_versions("1.0+b1", "1.0"), Ordering::Greater);
+        assert_eq!(compare_dpkg_versions("1:0.9", "1.0"), Ordering::Greater);
+        assert_eq!(compare_dpkg_versions("1.0-1", "1.0-1.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn dpkg_tilde() {
+        assert_eq!(compare_dpkg_versions("1.0~~", "1.0~"), Ordering::Less);
+        assert_eq!(compare_dpkg_versions("1.0~", "1.0"), Ordering::Less);
+        assert_eq!(compare_dpkg_versions("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare_dpkg_versions("1.0~rc1-1", "1.0~rc2-1"), Ordering::Less);
+        assert_eq!(compare_dpkg_versions("1.0-1~bpo12+1", "1.0-1"), Ordering::Less);
+        assert_eq!(compare_dpkg_versions("1.0-1", "1.0-1+deb12u1"), Ordering::Less);
+    }
+
+    #[test]
+    fn diff_detects_each_kind_of_change() {
+        let from = packages(&[
+            ("bash", &["5.2.26-3.fc40"]),
+            ("kernel", &["6.8.5-301.fc40", "6.8.9-300.fc40"]),
+            ("openssl", &["1:3.2.1-2.fc40"]),
+            ("vim", &["9.1.300-1.fc40"]),
+            ("zsh", &["5.9-10.fc40"]),
+        ]);
+        let to = packages(&[
+            ("bash", &["5.2.26-3.fc40"]),
+            ("htop", &["3.3.0-3.fc40"]),
+            ("kernel", &["6.8.9-300.fc40", "6.9.4-200.fc40"]),
+            ("openssl", &["3.3.0-1.fc40"]),
+            ("vim", &["9.1.300~rc1-1.fc40"]),
+        ]);
+
+        let changes: Vec<(String, &str)> = diff_packages("dnf", &from, &to)
+            .iter()
+            .map(|(name, change)| (name.clone(), change.kind()))
+            .collect();
+        let expected = [
+            ("htop", "added"),
+            ("kernel", "upgraded"),
+            // A lower epoch is older whatever the version
+            ("openssl", "downgraded"),
+            ("vim", "downgraded"),
+            ("zsh", "removed"),
+        ];
+        assert_eq!(changes.len(), expected.len());
+        for ((name, kind), (expected_name, expected_kind)) in changes.iter().zip(expected) {
+            assert_eq!(name, expected_name);
+            assert_eq!(*kind, expected_kind);
+        }
+    }
+
+    #[test]
+    fn diff_uses_dpkg_rules_for_apt() {
+        let from = packages(&[("libc6", &["2.36-9+deb12u7"])]);
+        let to = packages(&[("libc6", &["2.36-9~bpo11+1"])]);
+
+        let changes = diff_packages("apt", &from, &to);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].1.kind(), "downgraded");
+        assert_eq!(changes[0].1.to_string(), "2.36-9+deb12u7 -> 2.36-9~bpo11+1");
+    }
+}
@@ -0,0 +1,229 @@
+//! Paths whose contents should not roll back with the OS, such as logs and
+//! databases.
+//!
+//! Each `PERSISTENT_PATH` line in /etc/atomic-update.conf names a path and how
+//! it is kept:
+//!
+//! - `subvolume` (the default): the path is moved into its own subvolume at the
+//!   top level by `init` and mounted through /etc/fstab, so no root snapshot
+//!   ever contains it
+//! - `sync`: the path stays in the root, and is copied from the running root
+//!   into the new one whenever the next boot's root changes
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::btrfs_handler::{mount_top_level, TOP_LEVEL_MOUNT_POINT};
+use crate::btrfs_ioctl;
+use crate::config_handler::ConfigOpts;
+use crate::mount::{self, MountFlags, MountGuard};
+use crate::utils::{get_root_partition_device, run_command};
+
+#[derive(PartialEq)]
+pub enum PersistMode {
+    Subvolume,
+    Sync,
+}
+
+pub struct PersistentPath {
+    pub path: PathBuf,
+    pub mode: PersistMode,
+}
+
+impl PersistentPath {
+    /// Name of the subvolume holding this path, at the top level.
+    fn subvolume_name(&self) -> String {
+        format!("au-persistent{}", self.path.to_str().unwrap().replace('/', "-"))
+    }
+
+    /// Where this path lives inside the root filesystem at `root`.
+    fn in_root(&self, root: &Path) -> PathBuf {
+        root.join(self.path.strip_prefix("/").unwrap())
+    }
+}
+
+/// The persistent paths from `PERSISTENT_PATH` lines, skipping invalid ones.
+pub fn from_config(opts: &ConfigOpts) -> Vec<PersistentPath> {
+    let mut paths = Vec::new();
+
+    for setting in &opts.persistent_paths {
+        let mut fields = setting.split(' ');
+        let path = Path::new(fields.next().unwrap_or_default());
+        let mode = match fields.next().unwrap_or("subvolume") {
+            "subvolume" => PersistMode::Subvolume,
+            "sync" => PersistMode::Sync,
+            other => {
+                eprintln!(
+                    "Ignoring PERSISTENT_PATH {:?}, unknown mode {:?}, expected 'subvolume' or 'sync'",
+                    setting, other
+                );
+                continue;
+            }
+        };
+
+        if !path.is_absolute() || path == Path::new("/") {
+            eprintln!("Ignoring PERSISTENT_PATH {:?}, expected an absolute path below /", setting);
+            continue;
+        }
+
+        paths.push(PersistentPath {
+            path: path.to_path_buf(),
+            mode,
+        });
+    }
+
+    paths
+}
+
+/// Whether the running system's /etc/fstab already mounts something on `path`.
+fn has_fstab_entry(path: &Path) -> io::Result<bool> {
+    let contents = fs::read_to_string("/etc/fstab")?;
+
+    Ok(contents.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 1 && !fields[0].starts_with('#') && Path::new(fields[1]) == path
+    }))
+}
+
+fn copy_contents(source: &Path, target: &Path) -> io::Result<()> {
+    let output = run_command(
+        String::from("cp"),
+        Some(&[
+            "-a",
+            "--reflink=auto",
+            source.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ]),
+    )?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Failed copying {:?} to {:?}: {}",
+            source,
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Move every `subvolume` path not set up yet into its own subvolume, and
+/// mount it from /etc/fstab. Run by `init`.
+pub fn create_persistent_subvolumes(opts: &ConfigOpts) -> io::Result<()> {
+    let paths: Vec<PersistentPath> = from_config(opts)
+        .into_iter()
+        .filter(|p| p.mode == PersistMode::Subvolume)
+        .collect();
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let root_partition_device = get_root_partition_device();
+    if root_partition_device.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Failed to detect root partition device, please set ROOT_PARTITION in /etc/atomic-update.conf",
+        ));
+    }
+    let fs_uuid = btrfs_ioctl::filesystem_uuid(Path::new("/"))?;
+
+    let top_level_path = Path::new(TOP_LEVEL_MOUNT_POINT);
+    fs::create_dir_all(top_level_path)?;
+    let _top_level = mount_top_level(&root_partition_device, top_level_path)?;
+
+    for persistent in paths {
+        if has_fstab_entry(&persistent.path)? {
+            println!("{:?} is already mounted from /etc/fstab, leaving it", persistent.path);
+            continue;
+        }
+
+        let name = persistent.subvolume_name();
+        let subvolume = top_level_path.join(&name);
+        if !subvolume.exists() {
+            println!("Creating subvolume {} for {:?}", name, persistent.path);
+            btrfs_ioctl::create_subvolume(&subvolume)?;
+        }
+
+        if persistent.path.is_dir() {
+            copy_contents(&persistent.path.join("."), &subvolume)?;
+        } else {
+            fs::create_dir_all(&persistent.path)?;
+        }
+
+        let options = format!("subvol=/{}", name);
+        let mut fstab = OpenOptions::new().append(true).open("/etc/fstab")?;
+        writeln!(
+            fstab,
+            "UUID={} {} btrfs {} 0 0",
+            fs_uuid,
+            persistent.path.to_str().unwrap(),
+            options
+        )?;
+
+        // Mounted the way the new fstab line will be at boot
+        match mount::mount_filesystem(
+            Path::new(&root_partition_device),
+            &persistent.path,
+            "btrfs",
+            MountFlags::NONE,
+            Some(&options),
+        ) {
+            Ok(mount) => mount.keep(),
+            Err(e) => eprintln!("{}, it will be mounted at next boot", e),
+        }
+
+        println!(
+            "{:?} is now persistent. Its old contents are still in the root, hidden under the mount, and can be removed from a snapshot with 'au exec'",
+            persistent.path
+        );
+    }
+
+    Ok(())
+}
+
+/// Make the running system's persistent subvolumes visible in the snapshot at
+/// `root`, so a command writing to them writes to the real thing.
+pub fn bind_persistent_subvolumes(opts: &ConfigOpts, root: &Path) -> io::Result<Vec<MountGuard>> {
+    let mut mounts = Vec::new();
+
+    for persistent in from_config(opts) {
+        if persistent.mode != PersistMode::Subvolume || !btrfs_ioctl::is_subvolume(&persistent.path) {
+            continue;
+        }
+
+        let target = persistent.in_root(root);
+        fs::create_dir_all(&target)?;
+        mounts.push(mount::bind_mount(&persistent.path, &target)?);
+    }
+
+    Ok(mounts)
+}
+
+/// Replace every `sync` path in the root filesystem at `target_root` with the
+/// running system's copy, before it becomes the root.
+pub fn sync_persistent_paths(opts: &ConfigOpts, target_root: &Path) -> io::Result<()> {
+    for persistent in from_config(opts) {
+        if persistent.mode != PersistMode::Sync {
+            continue;
+        }
+
+        let target = persistent.in_root(target_root);
+        println!("Carrying {:?} over into {:?}", persistent.path, target_root);
+
+        match target.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        if persistent.path.symlink_metadata().is_ok() {
+            fs::create_dir_all(target.parent().unwrap())?;
+            copy_contents(&persistent.path, &target)?;
+        }
+    }
+
+    Ok(())
+}
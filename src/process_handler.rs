@@ -0,0 +1,151 @@
+//! Cleaning up processes a command leaves running in a snapshot.
+//!
+//! They are found through the cgroup the command ran in when there is one,
+//! so that a process which changed its root or directory is found all the
+//! same. Without cgroup v2 the root directory of every process is checked
+//! instead. Commands run in their own PID namespace need neither, as the
+//! kernel kills whatever they leave behind.
+
+use std::fs;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::resource_limits::Cgroup;
+
+extern "C" {
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+}
+
+const SIGKILL: c_int = 9;
+const SIGTERM: c_int = 15;
+
+/// How long processes get to exit after SIGTERM before they are sent SIGKILL.
+const TERM_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+pub struct StrayProcess {
+    pub pid: i32,
+    pub name: String,
+}
+
+fn process_name(pid: i32) -> String {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|c| c.trim().to_string())
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// Find the processes left running by a command, from its cgroup if it had
+/// one, or else by their root directory being `root` or inside it.
+fn stray_processes(root: &Path, cgroup: Option<&Cgroup>) -> Vec<StrayProcess> {
+    let cgroup = match cgroup {
+        Some(c) => c,
+        None => return processes_rooted_in(root),
+    };
+
+    match cgroup.processes() {
+        Ok(pids) => pids
+            .into_iter()
+            .map(|pid| StrayProcess {
+                pid,
+                name: process_name(pid),
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Could not list the processes in the command's cgroup: {}", e);
+            processes_rooted_in(root)
+        }
+    }
+}
+
+/// Find every process whose root directory is `root` or somewhere inside it,
+/// by reading the `/proc/<pid>/root` links.
+fn processes_rooted_in(root: &Path) -> Vec<StrayProcess> {
+    let mut processes = Vec::new();
+    let own_pid = std::process::id() as i32;
+
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Could not read /proc to look for stray processes: {}", e);
+            return processes;
+        }
+    };
+
+    for entry in entries.map_while(Result::ok) {
+        let pid = match entry.file_name().to_str().and_then(|n| n.parse::<i32>().ok()) {
+            Some(p) => p,
+            None => continue,
+        };
+        if pid == own_pid {
+            continue;
+        }
+
+        // Kernel threads and processes which exited meanwhile have no root
+        let process_root: PathBuf = match fs::read_link(entry.path().join("root")) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        if !process_root.starts_with(root) {
+            continue;
+        }
+
+        processes.push(StrayProcess {
+            pid,
+            name: process_name(pid),
+        });
+    }
+
+    processes
+}
+
+/// Send `signal` to every stray process, then wait up to `grace_period` for
+/// them to go away. Returns the processes which were signalled.
+fn signal_and_wait(
+    root: &Path,
+    cgroup: Option<&Cgroup>,
+    signal: c_int,
+    grace_period: Duration,
+) -> Vec<StrayProcess> {
+    let processes = stray_processes(root, cgroup);
+    for process in processes.iter() {
+        unsafe { kill(process.pid, signal) };
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < grace_period && !stray_processes(root, cgroup).is_empty() {
+        sleep(Duration::from_millis(100));
+    }
+
+    processes
+}
+
+/// Terminate anything left running inside the snapshot at `root` once the
+/// command there has finished, such as a gpg-agent or a daemon started by a
+/// package scriptlet, using the `cgroup` the command ran in if any. Processes
+/// are asked to exit with SIGTERM, and whatever is still around afterwards is
+/// sent SIGKILL.
+pub fn kill_stray_processes(root: &Path, cgroup: Option<&Cgroup>) -> Vec<StrayProcess> {
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+
+    let mut killed = signal_and_wait(&root, cgroup, SIGTERM, TERM_GRACE_PERIOD);
+    if killed.is_empty() {
+        return killed;
+    }
+
+    for process in signal_and_wait(&root, cgroup, SIGKILL, KILL_GRACE_PERIOD) {
+        if !killed.iter().any(|k| k.pid == process.pid) {
+            killed.push(process);
+        }
+    }
+
+    for process in stray_processes(&root, cgroup) {
+        eprintln!(
+            "Process {} ({}) is still running inside {:?} and could not be killed",
+            process.pid, process.name, root
+        );
+    }
+
+    killed
+}
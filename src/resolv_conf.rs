@@ -0,0 +1,115 @@
+//! Giving commands in a snapshot working name resolution without changing the
+//! snapshot's own `/etc/resolv.conf`.
+//!
+//! The host's resolver config is bind mounted over the snapshot's for as long
+//! as the command runs. A symlink, such as the one systemd-resolved uses, can't
+//! be mounted over without following it into the host, so it is moved aside
+//! and replaced with a placeholder file until the command is done.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mount::{self, MountGuard};
+
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+const BACKUP_NAME: &str = ".resolv.conf.au-original";
+
+/// Undoes everything `provide_host_resolv_conf` did when dropped.
+pub struct ResolvConfGuard {
+    target: PathBuf,
+    backup: Option<PathBuf>,
+    created_placeholder: bool,
+    mount: Option<MountGuard>,
+}
+
+impl Drop for ResolvConfGuard {
+    fn drop(&mut self) {
+        // Unmount first, so the snapshot's own file is what gets restored
+        drop(self.mount.take());
+
+        if self.created_placeholder {
+            if let Err(e) = fs::remove_file(&self.target) {
+                eprintln!("Failed removing placeholder {:?}: {}", self.target, e);
+            }
+        }
+
+        if let Some(backup) = &self.backup {
+            if let Err(e) = fs::rename(backup, &self.target) {
+                eprintln!(
+                    "Failed restoring {:?} from {:?}, please move it back manually: {}",
+                    self.target, backup, e
+                );
+            }
+        }
+    }
+}
+
+/// Put back a symlink left moved aside by an earlier run which was killed
+/// before it could clean up.
+fn restore_stale_backup(target: &Path, backup: &Path) -> io::Result<()> {
+    if backup.symlink_metadata().is_err() {
+        return Ok(());
+    }
+
+    println!("Restoring {:?} left behind by a previous run", target);
+    if target.symlink_metadata().is_ok() {
+        fs::remove_file(target)?;
+    }
+    fs::rename(backup, target)
+}
+
+/// Make the host's resolver configuration visible at `/etc/resolv.conf` in the
+/// snapshot at `root`, until the returned guard is dropped.
+pub fn provide_host_resolv_conf(root: &Path) -> io::Result<ResolvConfGuard> {
+    let etc = root.join("etc");
+    let target = etc.join("resolv.conf");
+    let backup_path = etc.join(BACKUP_NAME);
+
+    let mut guard = ResolvConfGuard {
+        target: target.clone(),
+        backup: None,
+        created_placeholder: false,
+        mount: None,
+    };
+
+    restore_stale_backup(&target, &backup_path)?;
+
+    // On the host, follow any symlink through to the file actually in use
+    let source = match fs::canonicalize(HOST_RESOLV_CONF) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!(
+                "The host has no usable {}, network access may not work: {}",
+                HOST_RESOLV_CONF, e
+            );
+            return Ok(guard);
+        }
+    };
+
+    match target.symlink_metadata() {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            fs::rename(&target, &backup_path)?;
+            guard.backup = Some(backup_path);
+            File::create(&target)?;
+            guard.created_placeholder = true;
+        }
+        Ok(meta) if meta.is_file() => {}
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a file or symlink", target),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(&etc)?;
+            File::create(&target)?;
+            guard.created_placeholder = true;
+        }
+        Err(e) => return Err(e),
+    }
+
+    guard.mount = Some(mount::bind_mount(&source, &target)?);
+
+    Ok(guard)
+}
@@ -0,0 +1,213 @@
+//! Keeping commands in a snapshot from starving the rest of the system.
+//!
+//! Limits are applied through a transient systemd scope when one is
+//! configured and available, and otherwise (or additionally) through the
+//! command's nice value and IO priority class. The scope is left to systemd,
+//! rather than creating cgroups behind its back.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::config_handler::ConfigOpts;
+use crate::utils::run_command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// IO scheduling classes understood by `ioprio_set(2)`.
+#[derive(Clone, Copy)]
+pub enum IoClass {
+    BestEffort,
+    Idle,
+}
+
+impl IoClass {
+    /// The value passed to `ioprio_set`, using the lowest priority within
+    /// the class.
+    pub fn ioprio(&self) -> i32 {
+        match self {
+            IoClass::BestEffort => (2 << 13) | 7,
+            IoClass::Idle => 3 << 13,
+        }
+    }
+}
+
+pub struct ResourceLimits {
+    pub cpu_weight: Option<u32>,
+    pub memory_max: Option<String>,
+    pub io_weight: Option<u32>,
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+}
+
+fn parse_setting<T: std::str::FromStr>(key: &str, value: &str) -> Option<T> {
+    if value.is_empty() {
+        return None;
+    }
+
+    match value.parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            eprintln!("Ignoring invalid {} {:?} in /etc/atomic-update.conf", key, value);
+            None
+        }
+    }
+}
+
+impl ResourceLimits {
+    pub fn none() -> ResourceLimits {
+        ResourceLimits {
+            cpu_weight: None,
+            memory_max: None,
+            io_weight: None,
+            nice: None,
+            io_class: None,
+        }
+    }
+
+    pub fn from_config(opts: &ConfigOpts) -> ResourceLimits {
+        let io_class = match opts.io_class.as_str() {
+            "" => None,
+            "idle" => Some(IoClass::Idle),
+            "best-effort" => Some(IoClass::BestEffort),
+            other => {
+                eprintln!(
+                    "Ignoring unknown IO_CLASS {:?}, expected 'idle' or 'best-effort'",
+                    other
+                );
+                None
+            }
+        };
+
+        ResourceLimits {
+            cpu_weight: parse_setting("CPU_WEIGHT", &opts.cpu_weight),
+            memory_max: Some(opts.memory_max.clone()).filter(|m| !m.is_empty()),
+            io_weight: parse_setting("IO_WEIGHT", &opts.io_weight),
+            nice: parse_setting("NICE", &opts.nice),
+            io_class,
+        }
+    }
+
+    fn wants_cgroup(&self) -> bool {
+        self.cpu_weight.is_some() || self.memory_max.is_some() || self.io_weight.is_some()
+    }
+
+    /// Create a cgroup enforcing the configured CPU, memory and IO limits, if
+    /// any are configured.
+    ///
+    /// Without cgroup v2 the limits can't be enforced, so the command is instead
+    /// niced and given idle IO priority, unless those were configured already.
+    pub fn create_cgroup(&mut self) -> Option<Cgroup> {
+        if !self.wants_cgroup() {
+            return None;
+        }
+
+        match Cgroup::create(self) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                eprintln!(
+                    "Could not set up a cgroup for resource limits, lowering priority instead: {}",
+                    e
+                );
+                self.nice.get_or_insert(10);
+                self.io_class.get_or_insert(IoClass::Idle);
+                None
+            }
+        }
+    }
+}
+
+/// The cgroup of a transient systemd scope for a single command, stopped again
+/// when dropped. The scope is started with a placeholder process, which keeps
+/// it alive until the command has joined it.
+pub struct Cgroup {
+    path: PathBuf,
+    unit: String,
+    placeholder: Child,
+}
+
+impl Cgroup {
+    fn create(limits: &ResourceLimits) -> io::Result<Cgroup> {
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "cgroup v2 is not mounted on /sys/fs/cgroup",
+            ));
+        }
+
+        let unit = format!("atomic-update-{}.scope", std::process::id());
+        let mut command = Command::new("systemd-run");
+        command.args(["--scope", "--quiet", "--collect", "--unit", &unit]);
+        if let Some(weight) = limits.cpu_weight {
+            command.arg(format!("--property=CPUWeight={}", weight));
+        }
+        if let Some(max) = &limits.memory_max {
+            command.arg(format!("--property=MemoryMax={}", max));
+        }
+        if let Some(weight) = limits.io_weight {
+            command.arg(format!("--property=IOWeight={}", weight));
+        }
+        let mut placeholder = command
+            .args(["--", "sleep", "infinity"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()?;
+
+        // The scope's cgroup is only known once systemd has started it
+        for _ in 0..50 {
+            if let Some(status) = placeholder.try_wait()? {
+                return Err(io::Error::other(format!("systemd-run exited with {}", status)));
+            }
+
+            let output = run_command(
+                String::from("systemctl"),
+                Some(&["show", "--property=ControlGroup", "--value", &unit]),
+            )?;
+            let control_group = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !control_group.is_empty() {
+                return Ok(Cgroup {
+                    path: Path::new(CGROUP_ROOT).join(control_group.trim_start_matches('/')),
+                    unit,
+                    placeholder,
+                });
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+
+        let _ = placeholder.kill();
+        let _ = placeholder.wait();
+        Err(io::Error::other(format!("{} did not start", unit)))
+    }
+
+    /// The file a process writes "0" to in order to join this group.
+    pub fn procs_path(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    /// The IDs of the processes in this group, apart from the placeholder.
+    pub fn processes(&self) -> io::Result<Vec<i32>> {
+        let placeholder = self.placeholder.id() as i32;
+        Ok(fs::read_to_string(self.procs_path())?
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .filter(|pid| *pid != placeholder)
+            .collect())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Stopping the scope kills whatever is left in it, and systemd removes
+        // the cgroup once it is empty
+        let stopped = run_command(String::from("systemctl"), Some(&["stop", &self.unit]));
+        if !stopped.is_ok_and(|output| output.status.success()) {
+            eprintln!("Failed stopping {}, please stop it manually", self.unit);
+            let _ = self.placeholder.kill();
+        }
+        let _ = self.placeholder.wait();
+    }
+}
@@ -0,0 +1,709 @@
+//! Running a command with a snapshot as its root filesystem.
+//!
+//! By default the command is started in fresh mount, PID, UTS and IPC
+//! namespaces and the snapshot is made its root with `pivot_root`, so it cannot
+//! see or signal host processes, host mounts are invisible to it, and anything
+//! it leaves running is killed by the kernel once it exits. A plain `chroot`
+//! is kept as a fallback for kernels or containers without namespace support.
+//!
+//! The kernel drops signals sent to the first process of a PID namespace
+//! unless it handles them, which package managers rarely do, so the command
+//! is not run as that process itself. A minimal init takes its place,
+//! passing signals on to the command and reaping anything left orphaned.
+
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_char, c_int, c_long, c_ulong, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::disk_usage;
+use crate::resource_limits::{Cgroup, ResourceLimits};
+use crate::signal_handler;
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+    fn mount(
+        source: *const c_char,
+        target: *const c_char,
+        filesystemtype: *const c_char,
+        mountflags: c_ulong,
+        data: *const c_void,
+    ) -> c_int;
+    fn umount2(target: *const c_char, flags: c_int) -> c_int;
+    fn chroot(path: *const c_char) -> c_int;
+    fn chdir(path: *const c_char) -> c_int;
+    fn execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char)
+        -> c_int;
+    fn pipe2(fds: *mut c_int, flags: c_int) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+    fn close(fd: c_int) -> c_int;
+    fn waitpid(pid: c_int, status: *mut c_int, options: c_int) -> c_int;
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+    fn open(path: *const c_char, flags: c_int, ...) -> c_int;
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+    fn _exit(status: c_int) -> !;
+    fn __errno_location() -> *mut c_int;
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+#[cfg(target_arch = "x86_64")]
+const SYS_CLONE: c_long = 56;
+#[cfg(target_arch = "x86_64")]
+const SYS_PIVOT_ROOT: c_long = 155;
+#[cfg(target_arch = "x86_64")]
+const SYS_IOPRIO_SET: c_long = 251;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const SYS_CLONE: c_long = 220;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const SYS_PIVOT_ROOT: c_long = 41;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const SYS_IOPRIO_SET: c_long = 30;
+#[cfg(target_arch = "x86")]
+const SYS_CLONE: c_long = 120;
+#[cfg(target_arch = "x86")]
+const SYS_PIVOT_ROOT: c_long = 217;
+#[cfg(target_arch = "x86")]
+const SYS_IOPRIO_SET: c_long = 289;
+#[cfg(target_arch = "arm")]
+const SYS_CLONE: c_long = 120;
+#[cfg(target_arch = "arm")]
+const SYS_PIVOT_ROOT: c_long = 218;
+#[cfg(target_arch = "arm")]
+const SYS_IOPRIO_SET: c_long = 314;
+
+const SIGCHLD: c_long = 17;
+const CLONE_NEWNS: c_long = 0x0002_0000;
+const CLONE_NEWUTS: c_long = 0x0400_0000;
+const CLONE_NEWIPC: c_long = 0x0800_0000;
+const CLONE_NEWPID: c_long = 0x2000_0000;
+
+const MS_NOSUID: c_ulong = 2;
+const MS_NODEV: c_ulong = 4;
+const MS_NOEXEC: c_ulong = 8;
+const MS_BIND: c_ulong = 4096;
+const MS_REC: c_ulong = 16384;
+const MS_PRIVATE: c_ulong = 1 << 18;
+
+const MNT_DETACH: c_int = 2;
+const O_WRONLY: c_int = 1;
+const O_CLOEXEC: c_int = 0o2000000;
+const EINTR: i32 = 4;
+const WNOHANG: c_int = 1;
+const SIGHUP: c_int = 1;
+const SIGINT: c_int = 2;
+const SIGQUIT: c_int = 3;
+const SIGKILL: c_int = 9;
+const SIGTERM: c_int = 15;
+const PRIO_PROCESS: c_int = 0;
+const IOPRIO_WHO_PROCESS: c_int = 1;
+
+/// Signals the init process passes on to the command.
+const FORWARDED_SIGNALS: [c_int; 4] = [SIGHUP, SIGINT, SIGQUIT, SIGTERM];
+
+/// The command started by the init process, once it is running.
+static COMMAND_PID: AtomicI32 = AtomicI32::new(0);
+
+/// How long a timed out command gets to exit after SIGTERM.
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often free space is checked while the command runs.
+const FREE_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a command gets to exit after SIGTERM once space runs low, kept
+/// short as it may still be writing.
+const LOW_SPACE_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Directories searched for the command inside the snapshot.
+const SEARCH_PATH: [&str; 6] = [
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// How a command is confined to the snapshot.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    /// Fresh mount, PID, UTS and IPC namespaces with the snapshot pivoted to `/`.
+    Namespaces,
+    /// A plain `chroot` into the snapshot, sharing everything else with the host.
+    Chroot,
+}
+
+impl Isolation {
+    pub fn from_config(value: &str) -> Isolation {
+        match value {
+            "chroot" => Isolation::Chroot,
+            "namespace" | "" => Isolation::Namespaces,
+            _ => {
+                eprintln!(
+                    "Unknown EXEC_BACKEND {:?}, expected 'namespace' or 'chroot'. Using namespaces.",
+                    value
+                );
+                Isolation::Namespaces
+            }
+        }
+    }
+}
+
+/// The steps the child takes before exec, reported back to the parent with
+/// the errno of whichever one failed.
+#[derive(Clone, Copy)]
+enum SetupStep {
+    JoinCgroup,
+    SetPriority,
+    MakeMountsPrivate,
+    BindRoot,
+    EnterRoot,
+    MountProc,
+    PivotRoot,
+    DetachOldRoot,
+    Chroot,
+    StartInit,
+    Exec,
+}
+
+impl SetupStep {
+    const ALL: [SetupStep; 11] = [
+        SetupStep::JoinCgroup,
+        SetupStep::SetPriority,
+        SetupStep::MakeMountsPrivate,
+        SetupStep::BindRoot,
+        SetupStep::EnterRoot,
+        SetupStep::MountProc,
+        SetupStep::PivotRoot,
+        SetupStep::DetachOldRoot,
+        SetupStep::Chroot,
+        SetupStep::StartInit,
+        SetupStep::Exec,
+    ];
+
+    fn description(&self) -> &'static str {
+        match self {
+            SetupStep::JoinCgroup => "joining the resource limit cgroup",
+            SetupStep::SetPriority => "lowering priority",
+            SetupStep::MakeMountsPrivate => "making mounts private",
+            SetupStep::BindRoot => "bind mounting the snapshot",
+            SetupStep::EnterRoot => "changing into the snapshot",
+            SetupStep::MountProc => "mounting /proc",
+            SetupStep::PivotRoot => "pivot_root",
+            SetupStep::DetachOldRoot => "detaching the host root",
+            SetupStep::Chroot => "chroot",
+            SetupStep::StartInit => "starting the command under an init process",
+            SetupStep::Exec => "executing the command",
+        }
+    }
+}
+
+/// Why the child could not be started, keeping the raw errno so the caller
+/// can tell missing namespace support from other failures.
+struct SpawnError {
+    /// The setup step which failed, or `None` if the child never started.
+    step: Option<SetupStep>,
+    error: io::Error,
+}
+
+impl SpawnError {
+    /// Whether this is the kernel or container refusing namespaces, with
+    /// EPERM, EINVAL or ENOSYS, either in `clone` or setting up the mounts.
+    fn namespaces_unsupported(&self) -> bool {
+        let namespace_step = match self.step {
+            None => true,
+            Some(step) => matches!(
+                step,
+                SetupStep::MakeMountsPrivate | SetupStep::MountProc | SetupStep::PivotRoot
+            ),
+        };
+        namespace_step && matches!(self.error.raw_os_error(), Some(1) | Some(22) | Some(38))
+    }
+
+    fn into_io_error(self, program: &CString) -> io::Error {
+        match self.step {
+            None => self.error,
+            Some(step) => io::Error::new(
+                self.error.kind(),
+                format!("Failed {} ({:?}): {}", step.description(), program, self.error),
+            ),
+        }
+    }
+}
+
+/// Everything the child needs, prepared up front so that nothing between
+/// `clone` and `execve` has to allocate.
+struct ChildSetup {
+    root: CString,
+    program: CString,
+    argv: Vec<CString>,
+    envp: Vec<CString>,
+    cgroup_procs: Option<CString>,
+    nice: Option<i32>,
+    ioprio: Option<i32>,
+}
+
+/// A command running inside a snapshot.
+pub struct SandboxChild {
+    pid: c_int,
+}
+
+impl SandboxChild {
+    /// Block until the command exits.
+    pub fn wait(self) -> io::Result<ExitStatus> {
+        let mut status: c_int = 0;
+        loop {
+            if unsafe { waitpid(self.pid, &mut status, 0) } >= 0 {
+                return Ok(ExitStatus::from_raw(status));
+            }
+
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(EINTR) {
+                return Err(error);
+            }
+        }
+    }
+
+    fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        let mut status: c_int = 0;
+        match unsafe { waitpid(self.pid, &mut status, WNOHANG) } {
+            0 => Ok(None),
+            n if n > 0 => Ok(Some(ExitStatus::from_raw(status))),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() == Some(EINTR) {
+                    return Ok(None);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Send the command `signal`, then SIGKILL it if it hasn't exited within
+    /// the grace period.
+    fn stop(self, signal: c_int) -> io::Result<ExitStatus> {
+        self.stop_within(signal, TERMINATE_GRACE_PERIOD)
+    }
+
+    /// Send the command `signal`, then SIGKILL it if it hasn't exited within
+    /// `grace_period`.
+    fn stop_within(self, signal: c_int, grace_period: Duration) -> io::Result<ExitStatus> {
+        unsafe { kill(self.pid, signal) };
+
+        let started = Instant::now();
+        while started.elapsed() < grace_period {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        unsafe { kill(self.pid, SIGKILL) };
+        self.wait()
+    }
+
+    /// Wait for the command to exit, stopping it if it runs for longer than
+    /// `timeout`, or if the free space on the filesystem at `free_space.0`
+    /// drops below `free_space.1` bytes. If atomic-update itself is
+    /// interrupted the signal is passed on to the command, which is waited for
+    /// before returning.
+    pub fn wait_with_limits(
+        self,
+        timeout: Option<Duration>,
+        free_space: Option<(&Path, u64)>,
+    ) -> io::Result<ExitStatus> {
+        let started = Instant::now();
+        let mut space_checked = started;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+
+            if let Some(signum) = signal_handler::pending() {
+                eprintln!("Interrupted, waiting for the command to stop");
+                self.stop(signum)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    format!("Interrupted by signal {}", signum),
+                ));
+            }
+
+            if let Some(limit) = timeout {
+                if started.elapsed() >= limit {
+                    eprintln!(
+                        "Command is still running after {} seconds, stopping it",
+                        limit.as_secs()
+                    );
+                    self.stop(SIGTERM)?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Command timed out after {} seconds", limit.as_secs()),
+                    ));
+                }
+            }
+
+            if let Some((path, minimum)) = free_space {
+                if space_checked.elapsed() >= FREE_SPACE_CHECK_INTERVAL {
+                    space_checked = Instant::now();
+                    if let Err(e) = disk_usage::ensure_free_space(path, minimum) {
+                        eprintln!("{}, stopping the command", e);
+                        self.stop_within(SIGTERM, LOW_SPACE_GRACE_PERIOD)?;
+                        return Err(e);
+                    }
+                }
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+fn to_cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} contains a NUL byte", String::from_utf8_lossy(bytes)),
+        )
+    })
+}
+
+/// Find `command` in the snapshot's standard binary directories, returning the
+/// path it will have once the snapshot is the root.
+fn resolve_command(root: &Path, command: &str) -> String {
+    if command.contains('/') {
+        return command.to_string();
+    }
+
+    for dir in SEARCH_PATH {
+        let candidate = format!("{}/{}", dir, command);
+        if root
+            .join(candidate.trim_start_matches('/'))
+            .symlink_metadata()
+            .is_ok()
+        {
+            return candidate;
+        }
+    }
+
+    command.to_string()
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const c_char> {
+    let mut pointers: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+    pointers.push(ptr::null());
+    pointers
+}
+
+/// Report a failed setup step to the parent, then exit.
+unsafe fn child_fail(error_fd: c_int, step: SetupStep) -> ! {
+    let report: [i32; 2] = [step as i32, *__errno_location()];
+    write(
+        error_fd,
+        report.as_ptr() as *const c_void,
+        std::mem::size_of_val(&report),
+    );
+    _exit(127);
+}
+
+extern "C" fn forward_signal(signum: c_int) {
+    let pid = COMMAND_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { kill(pid, signum) };
+    }
+}
+
+/// Fork off the command, returning in the new process. The calling process
+/// stays behind as the init of the PID namespace: it passes signals on to the
+/// command and reaps every process which exits, then exits with the
+/// command's status once it finishes. A command killed by a signal is
+/// reported with the shell's `128 + signal` status.
+unsafe fn run_init(error_fd: c_int) {
+    for signum in FORWARDED_SIGNALS {
+        signal(signum, forward_signal);
+    }
+
+    let pid = syscall(SYS_CLONE, SIGCHLD, 0 as c_long);
+    if pid < 0 {
+        child_fail(error_fd, SetupStep::StartInit);
+    }
+    if pid == 0 {
+        // The handlers are reset to the default by exec
+        return;
+    }
+    COMMAND_PID.store(pid as c_int, Ordering::SeqCst);
+
+    // Only the command reports errors, and the parent waits for it to
+    // close the pipe
+    close(error_fd);
+
+    loop {
+        let mut status: c_int = 0;
+        let reaped = waitpid(-1, &mut status, 0);
+        if reaped == pid as c_int {
+            let signum = status & 0x7f;
+            if signum == 0 {
+                _exit((status >> 8) & 0xff);
+            }
+            _exit(128 + signum);
+        }
+        if reaped < 0 && *__errno_location() != EINTR {
+            _exit(127);
+        }
+    }
+}
+
+/// Runs in the cloned child. Only async-signal-safe calls are allowed here.
+unsafe fn child_main(
+    setup: &ChildSetup,
+    argv: &[*const c_char],
+    envp: &[*const c_char],
+    isolation: Isolation,
+    error_fd: c_int,
+) -> ! {
+    let slash = c"/".as_ptr();
+    let dot = c".".as_ptr();
+
+    // Join the cgroup and drop priority before anything else, while the host's
+    // /sys/fs/cgroup is still reachable
+    if let Some(procs) = &setup.cgroup_procs {
+        let fd = open(procs.as_ptr(), O_WRONLY | O_CLOEXEC);
+        if fd < 0 || write(fd, c"0".as_ptr() as *const c_void, 1) < 0 {
+            child_fail(error_fd, SetupStep::JoinCgroup);
+        }
+        close(fd);
+    }
+    if let Some(nice) = setup.nice {
+        if setpriority(PRIO_PROCESS, 0, nice) < 0 {
+            child_fail(error_fd, SetupStep::SetPriority);
+        }
+    }
+    if let Some(ioprio) = setup.ioprio {
+        if syscall(
+            SYS_IOPRIO_SET,
+            IOPRIO_WHO_PROCESS as c_long,
+            0 as c_long,
+            ioprio as c_long,
+        ) < 0 {
+            child_fail(error_fd, SetupStep::SetPriority);
+        }
+    }
+
+    match isolation {
+        Isolation::Namespaces => {
+            // Keep our mounts from propagating back to the host
+            if mount(ptr::null(), slash, ptr::null(), MS_REC | MS_PRIVATE, ptr::null()) < 0 {
+                child_fail(error_fd, SetupStep::MakeMountsPrivate);
+            }
+            // pivot_root needs the new root to be a mount point
+            if mount(
+                setup.root.as_ptr(),
+                setup.root.as_ptr(),
+                ptr::null(),
+                MS_BIND | MS_REC,
+                ptr::null(),
+            ) < 0
+            {
+                child_fail(error_fd, SetupStep::BindRoot);
+            }
+            if chdir(setup.root.as_ptr()) < 0 {
+                child_fail(error_fd, SetupStep::EnterRoot);
+            }
+            // A fresh /proc, so only processes in our PID namespace show up
+            let proc = c"proc".as_ptr();
+            if mount(
+                proc,
+                proc,
+                proc,
+                MS_NOSUID | MS_NODEV | MS_NOEXEC,
+                ptr::null(),
+            ) < 0
+            {
+                child_fail(error_fd, SetupStep::MountProc);
+            }
+            if syscall(SYS_PIVOT_ROOT, dot, dot) < 0 {
+                child_fail(error_fd, SetupStep::PivotRoot);
+            }
+            // The host root is now stacked underneath, detach it entirely
+            if umount2(dot, MNT_DETACH) < 0 {
+                child_fail(error_fd, SetupStep::DetachOldRoot);
+            }
+        }
+        Isolation::Chroot => {
+            if chroot(setup.root.as_ptr()) < 0 {
+                child_fail(error_fd, SetupStep::Chroot);
+            }
+        }
+    }
+
+    if chdir(slash) < 0 {
+        child_fail(error_fd, SetupStep::EnterRoot);
+    }
+
+    if isolation == Isolation::Namespaces {
+        run_init(error_fd);
+    }
+
+    execve(setup.program.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    child_fail(error_fd, SetupStep::Exec);
+}
+
+/// Clone a child which confines itself to `setup.root` and execs the command.
+fn spawn(setup: &ChildSetup, isolation: Isolation) -> Result<SandboxChild, SpawnError> {
+    let argv = null_terminated(&setup.argv);
+    let envp = null_terminated(&setup.envp);
+    let failed = |error| SpawnError { step: None, error };
+
+    let mut fds: [c_int; 2] = [-1, -1];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } < 0 {
+        return Err(failed(io::Error::last_os_error()));
+    }
+    let [read_fd, write_fd] = fds;
+
+    let clone_flags = match isolation {
+        Isolation::Namespaces => CLONE_NEWNS | CLONE_NEWPID | CLONE_NEWUTS | CLONE_NEWIPC | SIGCHLD,
+        Isolation::Chroot => SIGCHLD,
+    };
+
+    // A raw clone without a new stack behaves like fork, but lets us ask for
+    // new namespaces for the child alone.
+    let pid = unsafe { syscall(SYS_CLONE, clone_flags, 0 as c_long) };
+    if pid == 0 {
+        unsafe {
+            close(read_fd);
+            child_main(setup, &argv, &envp, isolation, write_fd);
+        }
+    }
+
+    let clone_error = io::Error::last_os_error();
+    unsafe { close(write_fd) };
+    if pid < 0 {
+        unsafe { close(read_fd) };
+        return Err(failed(clone_error));
+    }
+
+    let child = SandboxChild { pid: pid as c_int };
+
+    // The pipe is closed on a successful exec, otherwise the child reports
+    // which step failed and why
+    let mut report: [i32; 2] = [0, 0];
+    let report_len = std::mem::size_of_val(&report);
+    let mut got = 0;
+    while got < report_len {
+        let n = unsafe {
+            read(
+                read_fd,
+                (report.as_mut_ptr() as *mut u8).add(got) as *mut c_void,
+                report_len - got,
+            )
+        };
+        if n == 0 {
+            break;
+        }
+        if n < 0 {
+            if io::Error::last_os_error().raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            break;
+        }
+        got += n as usize;
+    }
+    unsafe { close(read_fd) };
+
+    if got == report_len {
+        child.wait().map_err(failed)?;
+        return Err(SpawnError {
+            step: SetupStep::ALL.get(report[0] as usize).copied(),
+            error: io::Error::from_raw_os_error(report[1]),
+        });
+    }
+
+    Ok(child)
+}
+
+/// The environment commands in a snapshot start with: a fixed PATH, HOME,
+/// LANG and TERM, plus any variables in `allowlist` which are set for atomic-update
+/// itself, such as proxy settings. Nothing else is passed through.
+pub fn snapshot_environment(allowlist: &[String]) -> Vec<(String, String)> {
+    let mut env = vec![
+        (String::from("PATH"), SEARCH_PATH.join(":")),
+        (String::from("HOME"), String::from("/root")),
+        (
+            String::from("LANG"),
+            std::env::var("LANG").unwrap_or_else(|_| String::from("C.UTF-8")),
+        ),
+        (
+            String::from("TERM"),
+            std::env::var("TERM").unwrap_or_else(|_| String::from("dumb")),
+        ),
+    ];
+
+    for key in allowlist {
+        if env.iter().any(|(k, _)| k == key) {
+            continue;
+        }
+        if let Ok(value) = std::env::var(key) {
+            env.push((key.clone(), value));
+        }
+    }
+
+    env
+}
+
+/// Start `command` with the snapshot at `root` as its root filesystem, exactly
+/// the environment `env`, and the priority from `limits`. If `cgroup` is given
+/// the command joins it before doing anything else.
+///
+/// If namespaces are requested but the kernel refuses them, this warns and
+/// falls back to a plain chroot.
+pub fn spawn_in_root(
+    root: &Path,
+    command: &str,
+    args: &[&str],
+    env: &[(String, String)],
+    isolation: Isolation,
+    limits: &ResourceLimits,
+    cgroup: Option<&Cgroup>,
+) -> io::Result<SandboxChild> {
+    let program = resolve_command(root, command);
+
+    let mut argv = vec![to_cstring(command.as_bytes())?];
+    for arg in args {
+        argv.push(to_cstring(arg.as_bytes())?);
+    }
+
+    let mut envp = Vec::new();
+    for (key, value) in env {
+        envp.push(to_cstring(format!("{}={}", key, value).as_bytes())?);
+    }
+
+    let setup = ChildSetup {
+        root: to_cstring(root.as_os_str().as_bytes())?,
+        program: to_cstring(program.as_bytes())?,
+        argv,
+        envp,
+        cgroup_procs: cgroup
+            .map(|c| to_cstring(c.procs_path().as_os_str().as_bytes()))
+            .transpose()?,
+        nice: limits.nice,
+        ioprio: limits.io_class.map(|c| c.ioprio()),
+    };
+
+    let spawned = match spawn(&setup, isolation) {
+        Err(e) if isolation == Isolation::Namespaces && e.namespaces_unsupported() => {
+            eprintln!(
+                "Could not create namespaces ({}), falling back to chroot",
+                e.into_io_error(&setup.program)
+            );
+            spawn(&setup, Isolation::Chroot)
+        }
+        other => other,
+    };
+    spawned.map_err(|e| e.into_io_error(&setup.program))
+}
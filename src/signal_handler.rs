@@ -0,0 +1,44 @@
+//! Turning Ctrl+C, SIGTERM and SIGHUP into a clean abort.
+//!
+//! Once `install` has been called these signals no longer kill atomic-update.
+//! They are only recorded, and acted upon at points where it is safe to stop:
+//! the command in the snapshot is sent the signal and waited for, mounts are
+//! torn down and the half-finished snapshot is discarded. Nothing checks for
+//! signals while subvolumes are being renamed, so a swap always completes.
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+const SIGHUP: c_int = 1;
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn record_signal(signum: c_int) {
+    PENDING_SIGNAL.store(signum, Ordering::SeqCst);
+}
+
+/// Start catching SIGINT, SIGTERM and SIGHUP instead of dying on them.
+pub fn install() {
+    for signum in [SIGHUP, SIGINT, SIGTERM] {
+        unsafe { signal(signum, record_signal) };
+    }
+}
+
+/// The last signal received, if any.
+pub fn pending() -> Option<i32> {
+    match PENDING_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum),
+    }
+}
+
+/// The conventional exit status for a process stopped by `signum`.
+pub fn exit_code(signum: i32) -> i32 {
+    128 + signum
+}
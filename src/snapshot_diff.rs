@@ -0,0 +1,374 @@
+//! Finding which files differ between two snapshots.
+//!
+//! Both sides are snapshotted read-only, and the kernel is asked for a send
+//! stream of the second relative to the first without any file contents. The
+//! commands in that stream say exactly which paths were created, removed,
+//! written to or had their metadata changed.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::btrfs_ioctl;
+use crate::etc_merge::ETC_BASE;
+use crate::snapshot_metadata::METADATA_FILE;
+
+const STREAM_MAGIC: &[u8] = b"btrfs-stream\0";
+
+// Send stream commands, from the kernel's send.h
+const CMD_MKFILE: u16 = 3;
+const CMD_MKDIR: u16 = 4;
+const CMD_MKNOD: u16 = 5;
+const CMD_MKFIFO: u16 = 6;
+const CMD_MKSOCK: u16 = 7;
+const CMD_SYMLINK: u16 = 8;
+const CMD_RENAME: u16 = 9;
+const CMD_LINK: u16 = 10;
+const CMD_UNLINK: u16 = 11;
+const CMD_RMDIR: u16 = 12;
+const CMD_SET_XATTR: u16 = 13;
+const CMD_REMOVE_XATTR: u16 = 14;
+const CMD_WRITE: u16 = 15;
+const CMD_CLONE: u16 = 16;
+const CMD_TRUNCATE: u16 = 17;
+const CMD_CHMOD: u16 = 18;
+const CMD_CHOWN: u16 = 19;
+const CMD_UPDATE_EXTENT: u16 = 22;
+const CMD_FALLOCATE: u16 = 23;
+const CMD_FILEATTR: u16 = 24;
+const CMD_ENCODED_WRITE: u16 = 25;
+
+const ATTR_PATH: u16 = 15;
+const ATTR_PATH_TO: u16 = 16;
+
+/// The length of a command header: length, command and checksum.
+const CMD_HEADER_LEN: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Change {
+    /// Only ownership, permissions or extended attributes changed.
+    Metadata,
+    Modified,
+    Removed,
+    Added,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+            Change::Metadata => "metadata",
+        };
+        f.pad(name)
+    }
+}
+
+/// One command from a send stream, with the path attributes we care about.
+struct Command {
+    cmd: u16,
+    path: Option<String>,
+    path_to: Option<String>,
+}
+
+fn invalid_stream(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid send stream: {}", reason),
+    )
+}
+
+fn parse_stream(stream: &[u8]) -> io::Result<Vec<Command>> {
+    if !stream.starts_with(STREAM_MAGIC) {
+        return Err(invalid_stream("bad magic"));
+    }
+
+    let mut commands = Vec::new();
+    // Skip the magic and the u32 version
+    let mut pos = STREAM_MAGIC.len() + 4;
+
+    while pos + CMD_HEADER_LEN <= stream.len() {
+        let len = u32::from_le_bytes(stream[pos..pos + 4].try_into().unwrap()) as usize;
+        let cmd = u16::from_le_bytes(stream[pos + 4..pos + 6].try_into().unwrap());
+        pos += CMD_HEADER_LEN;
+
+        let end = pos + len;
+        if end > stream.len() {
+            return Err(invalid_stream("truncated command"));
+        }
+
+        let mut command = Command {
+            cmd,
+            path: None,
+            path_to: None,
+        };
+
+        // Attributes are (u16 type, u16 length, data)
+        let mut attr_pos = pos;
+        while attr_pos + 4 <= end {
+            let attr = u16::from_le_bytes(stream[attr_pos..attr_pos + 2].try_into().unwrap());
+            let attr_len =
+                u16::from_le_bytes(stream[attr_pos + 2..attr_pos + 4].try_into().unwrap()) as usize;
+            attr_pos += 4;
+            if attr_pos + attr_len > end {
+                return Err(invalid_stream("truncated attribute"));
+            }
+
+            let value = &stream[attr_pos..attr_pos + attr_len];
+            match attr {
+                ATTR_PATH => command.path = Some(String::from_utf8_lossy(value).into_owned()),
+                ATTR_PATH_TO => command.path_to = Some(String::from_utf8_lossy(value).into_owned()),
+                _ => {}
+            }
+            attr_pos += attr_len;
+        }
+
+        commands.push(command);
+        pos = end;
+    }
+
+    Ok(commands)
+}
+
+/// Work out what happened to each path from the commands of a stream.
+fn changes_from_commands(commands: Vec<Command>) -> BTreeMap<String, Change> {
+    let mut changes: BTreeMap<String, Change> = BTreeMap::new();
+
+    for command in commands {
+        let path = match command.path {
+            Some(p) => p,
+            None => continue,
+        };
+
+        match command.cmd {
+            CMD_MKFILE | CMD_MKDIR | CMD_MKNOD | CMD_MKFIFO | CMD_MKSOCK | CMD_SYMLINK | CMD_LINK => {
+                // A path removed and created again was replaced
+                let change = match changes.get(&path) {
+                    Some(Change::Removed) => Change::Modified,
+                    _ => Change::Added,
+                };
+                changes.insert(path, change);
+            }
+            CMD_RENAME => {
+                let to = match command.path_to {
+                    Some(t) => t,
+                    None => continue,
+                };
+
+                // New files are created under temporary names and renamed into
+                // place, so everything recorded below the old name moves along
+                let prefix = format!("{}/", path);
+                let moved: Vec<String> = changes
+                    .keys()
+                    .filter(|p| **p == path || p.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                let was_added = changes.get(&path) == Some(&Change::Added);
+
+                for old in moved {
+                    let change = changes.remove(&old).unwrap();
+                    changes.insert(format!("{}{}", to, &old[path.len()..]), change);
+                }
+
+                if !was_added {
+                    changes.insert(path, Change::Removed);
+                    changes.insert(to, Change::Added);
+                }
+            }
+            CMD_UNLINK | CMD_RMDIR => {
+                if changes.get(&path) == Some(&Change::Added) {
+                    changes.remove(&path);
+                } else {
+                    changes.insert(path, Change::Removed);
+                }
+            }
+            CMD_WRITE | CMD_CLONE | CMD_TRUNCATE | CMD_UPDATE_EXTENT | CMD_FALLOCATE
+            | CMD_ENCODED_WRITE => {
+                let change = changes.entry(path).or_insert(Change::Modified);
+                if *change < Change::Modified {
+                    *change = Change::Modified;
+                }
+            }
+            CMD_SET_XATTR | CMD_REMOVE_XATTR | CMD_CHMOD | CMD_CHOWN | CMD_FILEATTR => {
+                changes.entry(path).or_insert(Change::Metadata);
+            }
+            // Timestamps change on every directory something happened in
+            _ => {}
+        }
+    }
+
+    changes
+}
+
+/// Whether `path` is one of the files atomic-update keeps in every snapshot
+/// for itself, which differ between any two snapshots.
+fn is_bookkeeping(path: &str) -> bool {
+    [METADATA_FILE, ETC_BASE].iter().any(|name| {
+        path.strip_prefix(name)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// A read-only snapshot taken just for comparing, deleted when dropped.
+struct TemporarySnapshot {
+    path: PathBuf,
+}
+
+impl TemporarySnapshot {
+    fn create(source: &Path, path: PathBuf) -> io::Result<TemporarySnapshot> {
+        btrfs_ioctl::create_readonly_snapshot(source, &path)?;
+        Ok(TemporarySnapshot { path })
+    }
+}
+
+impl Drop for TemporarySnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = btrfs_ioctl::delete_subvolume(&self.path) {
+            eprintln!("Failed deleting temporary snapshot {:?}, please delete it manually: {}", self.path, e);
+        }
+    }
+}
+
+/// Compare the subvolumes at `from` and `to`, returning every changed path
+/// (relative to the subvolume, starting with `/`) and how it changed, apart
+/// from atomic-update's own metadata. The temporary snapshots are created in
+/// `scratch_dir`, which must be on the same filesystem.
+pub fn diff_subvolumes(from: &Path, to: &Path, scratch_dir: &Path) -> io::Result<Vec<(String, Change)>> {
+    let pid = std::process::id();
+    let from_snapshot = TemporarySnapshot::create(from, scratch_dir.join(format!("au-diff-{}-from", pid)))?;
+    let to_snapshot = TemporarySnapshot::create(to, scratch_dir.join(format!("au-diff-{}-to", pid)))?;
+
+    let stream = btrfs_ioctl::send_subvolume(
+        &to_snapshot.path,
+        Some(&from_snapshot.path),
+        btrfs_ioctl::BTRFS_SEND_FLAG_NO_FILE_DATA,
+    )?;
+
+    let changes = changes_from_commands(parse_stream(&stream)?);
+    Ok(changes
+        .into_iter()
+        .filter(|(path, _)| !is_bookkeeping(path))
+        .map(|(path, change)| (format!("/{}", path), change))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a command with string attributes, as the kernel would.
+    fn command(cmd: u16, attrs: &[(u16, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (attr, value) in attrs {
+            data.extend_from_slice(&attr.to_le_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(value.as_bytes());
+        }
+
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&cmd.to_le_bytes());
+        encoded.extend_from_slice(&[0; 4]);
+        encoded.extend_from_slice(&data);
+        encoded
+    }
+
+    fn stream(commands: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = STREAM_MAGIC.to_vec();
+        stream.extend_from_slice(&1u32.to_le_bytes());
+        for command in commands {
+            stream.extend_from_slice(command);
+        }
+        stream
+    }
+
+    fn changes(commands: &[Vec<u8>]) -> Vec<(String, Change)> {
+        changes_from_commands(parse_stream(&stream(commands)).unwrap())
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn parses_path_attributes() {
+        let commands = parse_stream(&stream(&[
+            command(CMD_RENAME, &[(ATTR_PATH, "o257-7-0"), (ATTR_PATH_TO, "etc/hosts")]),
+            command(CMD_CHMOD, &[(ATTR_PATH, "etc/hosts"), (99, "ignored")]),
+        ]))
+        .unwrap();
+
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].cmd, CMD_RENAME);
+        assert_eq!(commands[0].path.as_deref(), Some("o257-7-0"));
+        assert_eq!(commands[0].path_to.as_deref(), Some("etc/hosts"));
+        assert_eq!(commands[1].path.as_deref(), Some("etc/hosts"));
+        assert_eq!(commands[1].path_to, None);
+    }
+
+    #[test]
+    fn rejects_bad_streams() {
+        assert!(parse_stream(b"not a stream").is_err());
+
+        let mut truncated = stream(&[command(CMD_UNLINK, &[(ATTR_PATH, "etc/hosts")])]);
+        truncated.pop();
+        assert!(parse_stream(&truncated).is_err());
+
+        // An attribute claiming to be longer than its command
+        let mut overlong = command(CMD_UNLINK, &[(ATTR_PATH, "etc")]);
+        overlong[12] = 200;
+        assert!(parse_stream(&stream(&[overlong])).is_err());
+    }
+
+    #[test]
+    fn new_files_are_added_under_their_final_name() {
+        let found = changes(&[
+            command(CMD_MKDIR, &[(ATTR_PATH, "o258-7-0")]),
+            command(CMD_MKFILE, &[(ATTR_PATH, "o258-7-0/o259-7-0")]),
+            command(CMD_RENAME, &[(ATTR_PATH, "o258-7-0/o259-7-0"), (ATTR_PATH_TO, "o258-7-0/conf")]),
+            command(CMD_WRITE, &[(ATTR_PATH, "o258-7-0/conf")]),
+            command(CMD_RENAME, &[(ATTR_PATH, "o258-7-0"), (ATTR_PATH_TO, "opt/app")]),
+        ]);
+
+        assert!(found.len() == 2);
+        assert_eq!(found[0].0, "opt/app");
+        assert!(found[0].1 == Change::Added);
+        assert_eq!(found[1].0, "opt/app/conf");
+        assert!(found[1].1 == Change::Added);
+    }
+
+    #[test]
+    fn classifies_changes() {
+        let found = changes(&[
+            command(CMD_UNLINK, &[(ATTR_PATH, "usr/bin/old")]),
+            command(CMD_WRITE, &[(ATTR_PATH, "etc/hosts")]),
+            command(CMD_CHOWN, &[(ATTR_PATH, "etc/hosts")]),
+            command(CMD_CHMOD, &[(ATTR_PATH, "etc/shadow")]),
+            command(CMD_RENAME, &[(ATTR_PATH, "etc/a"), (ATTR_PATH_TO, "etc/b")]),
+            // Replaced by a file of a different type
+            command(CMD_RMDIR, &[(ATTR_PATH, "var/x")]),
+            command(CMD_SYMLINK, &[(ATTR_PATH, "var/x")]),
+            // Created and removed again between the two snapshots
+            command(CMD_MKFILE, &[(ATTR_PATH, "tmp/t")]),
+            command(CMD_UNLINK, &[(ATTR_PATH, "tmp/t")]),
+        ]);
+
+        let change_of = |path: &str| found.iter().find(|(p, _)| p == path).map(|(_, c)| *c);
+        assert!(change_of("usr/bin/old") == Some(Change::Removed));
+        assert!(change_of("etc/hosts") == Some(Change::Modified));
+        assert!(change_of("etc/shadow") == Some(Change::Metadata));
+        assert!(change_of("etc/a") == Some(Change::Removed));
+        assert!(change_of("etc/b") == Some(Change::Added));
+        assert!(change_of("var/x") == Some(Change::Modified));
+        assert!(change_of("tmp/t").is_none());
+    }
+
+    #[test]
+    fn bookkeeping_files() {
+        assert!(is_bookkeeping(".au-metadata"));
+        assert!(is_bookkeeping(".au-etc-base"));
+        assert!(is_bookkeeping(".au-etc-base/hosts"));
+        assert!(!is_bookkeeping(".au-etc-based"));
+        assert!(!is_bookkeeping("etc/.au-metadata"));
+    }
+}
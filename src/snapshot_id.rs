@@ -0,0 +1,89 @@
+//! Numbering snapshots so that no number is ever used twice.
+//!
+//! The next number is kept in `atomic-update-state` at the top level of the
+//! filesystem, outside every root, so it is neither rolled back nor lost
+//! when roots are swapped. Numbers of deleted snapshots are not reused, and a
+//! snapshot keeps its number in its metadata after being renamed into the
+//! root or a rollback slot.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::btrfs_handler::list_snapshots;
+
+extern "C" {
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+}
+
+const LOCK_EX: c_int = 2;
+const EINTR: i32 = 4;
+
+const STATE_FILE: &str = "atomic-update-state";
+/// Held while a number is taken. The state file itself is replaced on every
+/// write, so a lock on it would not be seen by the next process to open it.
+const LOCK_FILE: &str = "atomic-update-state.lock";
+
+/// The highest number of any snapshot on the filesystem, for carrying on
+/// from the numbers used before the state file existed.
+fn highest_existing_id() -> io::Result<u64> {
+    Ok(list_snapshots()?
+        .iter()
+        .filter_map(|s| s.id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0))
+}
+
+fn read_next_id(state_path: &Path) -> io::Result<Option<u64>> {
+    let contents = match fs::read_to_string(state_path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    for line in contents.lines() {
+        if let Some(value) = line.strip_prefix("NEXT_ID ") {
+            return value.trim().parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid NEXT_ID {:?} in {:?}", value, state_path),
+                )
+            });
+        }
+    }
+
+    Ok(None)
+}
+
+/// Take the next snapshot number, with the top level of the filesystem
+/// mounted at `top_level`.
+pub fn allocate(top_level: &Path) -> io::Result<u64> {
+    let state_path = top_level.join(STATE_FILE);
+
+    // Released when the file is closed on return
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(top_level.join(LOCK_FILE))?;
+    while unsafe { flock(lock.as_raw_fd(), LOCK_EX) } < 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(EINTR) {
+            return Err(error);
+        }
+    }
+
+    let id = match read_next_id(&state_path)? {
+        Some(id) => id,
+        None => highest_existing_id()? + 1,
+    };
+
+    // Replace the file in one step, so a crash can't leave it empty
+    let temp_path = top_level.join(format!("{}.new", STATE_FILE));
+    fs::write(&temp_path, format!("NEXT_ID {}\n", id + 1))?;
+    fs::rename(&temp_path, &state_path)?;
+
+    Ok(id)
+}
@@ -0,0 +1,325 @@
+//! What atomic-update knows about each snapshot.
+//!
+//! Metadata is kept in a `KEY value` file at the top of the snapshot itself, so
+//! it travels with the subvolume through the renames of a swap. A new snapshot
+//! starts out with a copy of the root's file, which is overwritten straight
+//! away.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::btrfs_handler::Operation;
+use crate::btrfs_ioctl;
+
+pub const METADATA_FILE: &str = ".au-metadata";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SnapshotState {
+    /// The command is still running, or atomic-update was killed while it did.
+    Building,
+    /// The command failed.
+    Failed,
+    /// The command succeeded but the snapshot failed its health checks.
+    CheckFailed,
+    /// The snapshot passed its health checks.
+    Ready,
+    /// The snapshot passed its health checks and was left for promoting later.
+    Prepared,
+    /// The snapshot will be swapped in when the system shuts down.
+    Staged,
+}
+
+impl SnapshotState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnapshotState::Building => "building",
+            SnapshotState::Failed => "failed",
+            SnapshotState::CheckFailed => "check-failed",
+            SnapshotState::Ready => "ready",
+            SnapshotState::Prepared => "prepared",
+            SnapshotState::Staged => "staged",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<SnapshotState> {
+        match name {
+            "building" => Some(SnapshotState::Building),
+            "failed" => Some(SnapshotState::Failed),
+            "check-failed" => Some(SnapshotState::CheckFailed),
+            "ready" => Some(SnapshotState::Ready),
+            "prepared" => Some(SnapshotState::Prepared),
+            "staged" => Some(SnapshotState::Staged),
+            _ => None,
+        }
+    }
+}
+
+pub struct SnapshotMetadata {
+    /// The number the snapshot was created under, kept after it is renamed
+    /// into the root or a rollback slot.
+    pub id: Option<u64>,
+    /// The btrfs UUID of the subvolume, which changes if it is replaced.
+    pub uuid: Option<String>,
+    /// When the snapshot was taken, in seconds since the epoch.
+    pub created: Option<u64>,
+    pub operation: Operation,
+    pub state: SnapshotState,
+    /// `<check> <reason>` for every health check which failed.
+    pub failed_checks: Vec<String>,
+    /// Paths in /etc changed both on the live system and in the snapshot,
+    /// which could not be merged.
+    pub etc_conflicts: Vec<String>,
+    /// The snapshot this one is a promoted copy of.
+    pub promoted_from: Option<String>,
+    /// Describes the change, from `--message`.
+    pub message: Option<String>,
+    /// Set on snapshots which must never be deleted automatically, with a
+    /// note on why, which may be empty.
+    pub pinned: Option<String>,
+    /// Names the snapshot can be referred to by instead of its number.
+    pub tags: Vec<String>,
+}
+
+impl SnapshotMetadata {
+    pub fn new(operation: Operation) -> SnapshotMetadata {
+        SnapshotMetadata {
+            id: None,
+            uuid: None,
+            created: None,
+            operation,
+            state: SnapshotState::Building,
+            failed_checks: Vec::new(),
+            etc_conflicts: Vec::new(),
+            promoted_from: None,
+            message: None,
+            pinned: None,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn read(snapshot_path: &Path) -> io::Result<SnapshotMetadata> {
+        let path = snapshot_path.join(METADATA_FILE);
+        let contents = fs::read_to_string(&path)?;
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} has no valid {}", path, what),
+            )
+        };
+
+        let mut id = None;
+        let mut uuid = None;
+        let mut created = None;
+        let mut operation = None;
+        let mut state = None;
+        let mut failed_checks = Vec::new();
+        let mut etc_conflicts = Vec::new();
+        let mut promoted_from = None;
+        let mut message = None;
+        let mut pinned = None;
+        let mut tags = Vec::new();
+
+        for line in contents.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "ID" => id = value.parse().ok(),
+                "UUID" => uuid = Some(value.to_string()),
+                "CREATED" => created = value.parse().ok(),
+                "OPERATION" => operation = Operation::from_name(value),
+                "STATE" => state = SnapshotState::from_name(value),
+                "FAILED_CHECK" => failed_checks.push(value.to_string()),
+                "ETC_CONFLICT" => etc_conflicts.push(value.to_string()),
+                "PROMOTED_FROM" => promoted_from = Some(value.to_string()),
+                "MESSAGE" => message = Some(value.to_string()),
+                "PINNED" => pinned = Some(value.to_string()),
+                "TAG" => tags.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(SnapshotMetadata {
+            id,
+            uuid,
+            created,
+            operation: operation.ok_or_else(|| invalid("OPERATION"))?,
+            state: state.ok_or_else(|| invalid("STATE"))?,
+            failed_checks,
+            etc_conflicts,
+            promoted_from,
+            message,
+            pinned,
+            tags,
+        })
+    }
+
+    pub fn write(&self, snapshot_path: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        if let Some(id) = self.id {
+            contents += &format!("ID {}\n", id);
+        }
+        if let Some(uuid) = &self.uuid {
+            contents += &format!("UUID {}\n", uuid);
+        }
+        if let Some(created) = self.created {
+            contents += &format!("CREATED {}\n", created);
+        }
+        contents += &format!(
+            "OPERATION {}\nSTATE {}\n",
+            self.operation.name(),
+            self.state.name()
+        );
+        for check in &self.failed_checks {
+            contents += &format!("FAILED_CHECK {}\n", check);
+        }
+        for path in &self.etc_conflicts {
+            contents += &format!("ETC_CONFLICT {}\n", path);
+        }
+        if let Some(id) = &self.promoted_from {
+            contents += &format!("PROMOTED_FROM {}\n", id);
+        }
+        if let Some(message) = &self.message {
+            contents += &format!("MESSAGE {}\n", message);
+        }
+        if let Some(note) = &self.pinned {
+            contents += &format!("PINNED {}\n", note);
+        }
+        for tag in &self.tags {
+            contents += &format!("TAG {}\n", tag);
+        }
+
+        fs::write(snapshot_path.join(METADATA_FILE), contents)
+    }
+
+    /// Write the metadata, warning rather than failing if it can't be.
+    pub fn save(&self, snapshot_path: &Path) {
+        if let Err(e) = self.write(snapshot_path) {
+            eprintln!("Failed writing metadata of {:?}: {}", snapshot_path, e);
+        }
+    }
+
+    /// Record the number, UUID and creation time of the newly created snapshot
+    /// at `snapshot_path`, replacing any copied from the snapshot it was
+    /// taken of.
+    pub fn identify(&mut self, snapshot_path: &Path) {
+        self.id = snapshot_path.file_name().and_then(|n| n.to_str()).and_then(|n| n.parse().ok());
+        match btrfs_ioctl::subvolume_info(snapshot_path) {
+            Ok(info) => {
+                self.uuid = Some(info.uuid);
+                self.created = Some(info.created);
+            }
+            Err(e) => {
+                eprintln!("Could not look up {:?}: {}", snapshot_path, e);
+                self.uuid = None;
+                self.created = None;
+            }
+        }
+    }
+
+    /// Whether `name` from the command line refers to this snapshot, by the
+    /// number it was created under, its UUID or one of its tags.
+    pub fn answers_to(&self, name: &str) -> bool {
+        self.id.is_some_and(|id| id.to_string() == name)
+            || self.uuid.as_deref() == Some(name)
+            || self.tags.iter().any(|tag| tag == name)
+    }
+
+    /// Whether the snapshot at `snapshot_path` is pinned. Snapshots without
+    /// readable metadata are not.
+    pub fn is_pinned(snapshot_path: &Path) -> bool {
+        SnapshotMetadata::read(snapshot_path).is_ok_and(|m| m.pinned.is_some())
+    }
+
+    pub fn set_state(&mut self, snapshot_path: &Path, state: SnapshotState) {
+        self.state = state;
+        self.save(snapshot_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("au-metadata-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("round-trip");
+
+        let mut metadata = SnapshotMetadata::new(Operation::Promote);
+        metadata.id = Some(42);
+        metadata.uuid = Some(String::from("0f8fad5b-d9cb-469f-a165-70867728950e"));
+        metadata.created = Some(1_700_000_000);
+        metadata.state = SnapshotState::CheckFailed;
+        metadata.failed_checks = vec![String::from("kernel no kernel in /boot")];
+        metadata.etc_conflicts = vec![String::from("/etc/hosts"), String::from("/etc/fstab")];
+        metadata.promoted_from = Some(String::from("7"));
+        metadata.message = Some(String::from("before the big upgrade"));
+        metadata.pinned = Some(String::new());
+        metadata.tags = vec![String::from("known-good"), String::from("lts")];
+        metadata.write(&dir).unwrap();
+
+        let read = SnapshotMetadata::read(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.id, Some(42));
+        assert_eq!(read.uuid, metadata.uuid);
+        assert_eq!(read.created, Some(1_700_000_000));
+        assert_eq!(read.operation.name(), "promote");
+        assert!(read.state == SnapshotState::CheckFailed);
+        assert_eq!(read.failed_checks, metadata.failed_checks);
+        assert_eq!(read.etc_conflicts, metadata.etc_conflicts);
+        assert_eq!(read.promoted_from, metadata.promoted_from);
+        assert_eq!(read.message, metadata.message);
+        assert_eq!(read.pinned, Some(String::new()));
+        assert_eq!(read.tags, metadata.tags);
+    }
+
+    #[test]
+    fn optional_fields_stay_unset() {
+        let dir = temp_dir("minimal");
+        SnapshotMetadata::new(Operation::Update).write(&dir).unwrap();
+
+        let read = SnapshotMetadata::read(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.id, None);
+        assert_eq!(read.uuid, None);
+        assert_eq!(read.operation.name(), "update");
+        assert!(read.state == SnapshotState::Building);
+        assert_eq!(read.pinned, None);
+        assert!(read.tags.is_empty());
+    }
+
+    #[test]
+    fn answers_to_number_uuid_and_tags() {
+        let mut metadata = SnapshotMetadata::new(Operation::Update);
+        metadata.id = Some(12);
+        metadata.uuid = Some(String::from("0f8fad5b-d9cb-469f-a165-70867728950e"));
+        metadata.tags = vec![String::from("known-good")];
+
+        assert!(metadata.answers_to("12"));
+        assert!(metadata.answers_to("0f8fad5b-d9cb-469f-a165-70867728950e"));
+        assert!(metadata.answers_to("known-good"));
+        assert!(!metadata.answers_to("1"));
+        assert!(!metadata.answers_to("0f8fad5b"));
+        assert!(!SnapshotMetadata::new(Operation::Update).answers_to("12"));
+    }
+
+    #[test]
+    fn missing_state_is_invalid() {
+        let dir = temp_dir("invalid");
+        fs::write(dir.join(METADATA_FILE), "OPERATION update\n").unwrap();
+
+        let error = SnapshotMetadata::read(&dir).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,182 @@
+//! Moving snapshots between machines as `btrfs send` streams.
+//!
+//! A snapshot is exported by making it read-only for as long as the stream
+//! is written, rather than by sending a temporary copy, so that its UUID is
+//! what the receiving side records. A later export relative to it as parent
+//! can then be imported on any machine which imported it before.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::btrfs_ioctl;
+use crate::snapshot_id;
+use crate::snapshot_metadata::SnapshotMetadata;
+use crate::utils::run_command;
+
+const STREAM_MAGIC: &[u8] = b"btrfs-stream\0";
+const CMD_SUBVOL: u16 = 1;
+const CMD_SNAPSHOT: u16 = 2;
+const ATTR_PATH: u16 = 15;
+
+/// Keeps a subvolume read-only while it exists, restoring it afterwards if it
+/// was writable before.
+struct ReadOnlyGuard {
+    path: PathBuf,
+    was_writable: bool,
+}
+
+impl ReadOnlyGuard {
+    fn new(path: &Path) -> io::Result<ReadOnlyGuard> {
+        let was_writable = !btrfs_ioctl::is_readonly(path)?;
+        if was_writable {
+            btrfs_ioctl::set_readonly(path, true)?;
+        }
+
+        Ok(ReadOnlyGuard {
+            path: path.to_path_buf(),
+            was_writable,
+        })
+    }
+}
+
+impl Drop for ReadOnlyGuard {
+    fn drop(&mut self) {
+        if !self.was_writable {
+            return;
+        }
+        if let Err(e) = btrfs_ioctl::set_readonly(&self.path, false) {
+            eprintln!("Failed making {:?} writable again: {}", self.path, e);
+        }
+    }
+}
+
+/// Write the snapshot at `path` to `file` as a send stream, containing only
+/// the differences from `parent` when given.
+pub fn export_snapshot(path: &Path, parent: Option<&Path>, file: &Path) -> io::Result<()> {
+    let _readonly = ReadOnlyGuard::new(path)?;
+    let _parent_readonly = parent.map(ReadOnlyGuard::new).transpose()?;
+
+    let out = File::create(file)?;
+    if let Err(e) = btrfs_ioctl::send_subvolume_to(path, parent, 0, &out) {
+        drop(out);
+        let _ = fs::remove_file(file);
+        return Err(e);
+    }
+
+    out.sync_all()
+}
+
+/// The name of the subvolume a send stream creates, from its first command.
+fn received_name(file: &Path) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a send stream", file));
+
+    // Magic, version, then a command header: length, command, checksum
+    let mut stream = File::open(file)?;
+    let mut header = [0u8; 27];
+    stream.read_exact(&mut header).map_err(|_| invalid())?;
+    if !header.starts_with(STREAM_MAGIC) {
+        return Err(invalid());
+    }
+    let len = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+    let cmd = u16::from_le_bytes(header[21..23].try_into().unwrap());
+    if cmd != CMD_SUBVOL && cmd != CMD_SNAPSHOT {
+        return Err(invalid());
+    }
+
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).map_err(|_| invalid())?;
+
+    // Attributes are (u16 type, u16 length, data)
+    let mut pos = 0;
+    while pos + 4 <= data.len() {
+        let attr = u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap());
+        let attr_len = u16::from_le_bytes(data[pos + 2..pos + 4].try_into().unwrap()) as usize;
+        let value = data.get(pos + 4..pos + 4 + attr_len).ok_or_else(invalid)?;
+        if attr == ATTR_PATH {
+            return Ok(String::from_utf8_lossy(value).into_owned());
+        }
+        pos += 4 + attr_len;
+    }
+
+    Err(invalid())
+}
+
+/// Receive the send stream in `file` as a new snapshot in `snapshots_dir`,
+/// numbered like any other, with the top level of the filesystem mounted at
+/// `top_level`. Returns the new snapshot's path. It is left read-only, so that
+/// later incremental exports based on it can be imported.
+pub fn import_snapshot(file: &Path, snapshots_dir: &Path, top_level: &Path) -> io::Result<PathBuf> {
+    // The stream names the subvolume after the snapshot it was sent from, so
+    // it is received into a directory of its own rather than next to
+    // snapshots which may have the same name.
+    let name = received_name(file)?;
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} creates an unexpected subvolume {:?}", file, name),
+        ));
+    }
+    require_btrfs_receive()?;
+    let scratch_dir = snapshots_dir.join(format!(".import-{}", std::process::id()));
+    fs::create_dir(&scratch_dir)?;
+    let received_path = scratch_dir.join(&name);
+
+    let imported = receive_into(file, &scratch_dir).and_then(|_| {
+        // A read-only subvolume can't be moved to another directory, and
+        // the metadata it carries describes the snapshot it was sent from
+        btrfs_ioctl::set_readonly(&received_path, false)?;
+        let path = snapshots_dir.join(snapshot_id::allocate(top_level)?.to_string());
+        fs::rename(&received_path, &path)?;
+
+        if let Ok(mut metadata) = SnapshotMetadata::read(&path) {
+            metadata.identify(&path);
+            metadata.save(&path);
+        }
+        if let Err(e) = btrfs_ioctl::set_readonly(&path, true) {
+            eprintln!("Failed making {:?} read-only again: {}", path, e);
+        }
+        Ok(path)
+    });
+
+    // Don't leave a partly received subvolume behind
+    if imported.is_err() && btrfs_ioctl::is_subvolume(&received_path) {
+        if let Err(e) = btrfs_ioctl::delete_subvolume(&received_path) {
+            eprintln!("Failed deleting {:?}: {}", received_path, e);
+        }
+    }
+    if let Err(e) = fs::remove_dir(&scratch_dir) {
+        eprintln!("Failed removing {:?}, please remove it manually: {}", scratch_dir, e);
+    }
+
+    imported
+}
+
+/// Receiving is left to `btrfs receive`, so importing is the one command
+/// which needs btrfs-progs installed.
+fn require_btrfs_receive() -> io::Result<()> {
+    match run_command(String::from("btrfs"), Some(&["--version"])) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "importing needs the 'btrfs' command, please install btrfs-progs",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn receive_into(file: &Path, dir: &Path) -> io::Result<()> {
+    let output = run_command(
+        String::from("btrfs"),
+        Some(&["receive", "-f", file.to_str().unwrap(), dir.to_str().unwrap()]),
+    )?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "btrfs receive failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
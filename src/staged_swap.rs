@@ -0,0 +1,144 @@
+//! Deferring the swap of a prepared snapshot until the system shuts down.
+//!
+//! With `DEFER_SWAP yes` a snapshot which is ready is only recorded in
+//! `/.au-snapshots/staged-swap`, and a systemd unit is started whose `ExecStop`
+//! runs `atomic-update apply-staged` during shutdown. Anything written to the
+//! running root until then, in /etc or the persistent paths, is carried over
+//! at that point instead of being lost.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::btrfs_handler::archive_snapshot;
+use crate::btrfs_ioctl;
+use crate::snapshot_metadata::{SnapshotMetadata, SnapshotState};
+use crate::utils::run_command;
+
+const STAGED_SWAP_FILE: &str = "/.au-snapshots/staged-swap";
+
+const UNIT_NAME: &str = "atomic-update-swap.service";
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+fn systemctl(args: &[&str]) -> io::Result<()> {
+    let output = run_command(String::from("systemctl"), Some(args))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Write the unit applying the staged swap, and start it so that it is
+/// stopped, and runs its `ExecStop`, when the system shuts down.
+fn start_shutdown_unit() -> io::Result<()> {
+    let exe = env::current_exe()?;
+    let unit = format!(
+        "[Unit]
+Description=Swap in the root snapshot staged by atomic-update
+# Stopped before local filesystems are unmounted
+After=local-fs.target
+RequiresMountsFor=/
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/bin/true
+ExecStop={} apply-staged
+TimeoutStopSec=30min
+",
+        exe.to_str().unwrap()
+    );
+
+    fs::write(Path::new(UNIT_DIR).join(UNIT_NAME), unit)?;
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["start", UNIT_NAME])
+}
+
+/// Arrange for `snapshot_path` to be swapped in at shutdown, replacing any
+/// snapshot staged before.
+pub fn stage(snapshot_path: &Path) -> io::Result<()> {
+    if let Some(previous) = staged_snapshot()? {
+        if previous != snapshot_path {
+            println!("Replacing {:?}, which was staged before", previous);
+            unstage(&previous);
+        }
+    }
+
+    let uuid = btrfs_ioctl::subvolume_info(snapshot_path)?.uuid;
+    fs::write(
+        STAGED_SWAP_FILE,
+        format!("SNAPSHOT {}\nUUID {}\n", snapshot_path.to_str().unwrap(), uuid),
+    )?;
+
+    if let Err(e) = start_shutdown_unit() {
+        clear()?;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Mark a snapshot which is no longer going to be swapped in as prepared and
+/// make it read-only, so that it is kept for promoting like any other
+/// rather than passed over as still staged.
+pub fn unstage(snapshot_path: &Path) {
+    if !snapshot_path.is_dir() {
+        return;
+    }
+
+    match SnapshotMetadata::read(snapshot_path) {
+        Ok(mut metadata) if metadata.state == SnapshotState::Staged => {
+            metadata.set_state(snapshot_path, SnapshotState::Prepared);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Could not read the metadata of {:?}: {}", snapshot_path, e),
+    }
+    archive_snapshot(snapshot_path);
+}
+
+/// The snapshot waiting to be swapped in at shutdown, if any. The record is
+/// inside the root, so snapshots taken while a swap is staged carry a copy of
+/// it into the next boot. One whose subvolume is gone, or is no longer the one
+/// which was staged, is stale and is cleared.
+pub fn staged_snapshot() -> io::Result<Option<PathBuf>> {
+    let contents = match fs::read_to_string(STAGED_SWAP_FILE) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut path = None;
+    let mut uuid = None;
+    for line in contents.lines() {
+        if line.starts_with("SNAPSHOT") {
+            path = line.split(' ').next_back().map(PathBuf::from);
+        } else if line.starts_with("UUID") {
+            uuid = line.split(' ').next_back();
+        }
+    }
+
+    if let (Some(path), Some(uuid)) = (&path, uuid) {
+        let is_staged = btrfs_ioctl::is_subvolume(path) && btrfs_ioctl::subvolume_info(path)?.uuid == uuid;
+        if !is_staged {
+            println!("{:?} is no longer the snapshot which was staged, forgetting it", path);
+            clear()?;
+            return Ok(None);
+        }
+    }
+
+    Ok(path)
+}
+
+/// Forget the staged snapshot, so nothing happens at shutdown.
+pub fn clear() -> io::Result<()> {
+    match fs::remove_file(STAGED_SWAP_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -1,54 +1,10 @@
 use std::fs;
-use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::os::raw::{c_char, c_int, c_long};
 use std::path::Path;
-use std::process::{exit, Command, Stdio};
+use std::process::{exit, Command};
 
 use crate::btrfs_handler::is_root_user;
-
-pub fn run_command_and_stream_out(
-    cmd_to_run: std::string::String,
-    args_for_cmd: &[&str],
-) -> Result<(), Error> {
-    let stdout = Command::new(cmd_to_run)
-        .args(args_for_cmd)
-        .stdout(Stdio::piped())
-        .spawn()?
-        .stdout
-        .ok_or_else(|| Error::new(ErrorKind::Other, "Could not capture standard output."))?;
-
-    let reader = BufReader::new(stdout);
-
-    reader
-        .lines()
-        .map_while(Result::ok)
-        .filter(|line| line.contains("usb"))
-        .for_each(|line| println!("{}", line));
-
-    Ok(())
-}
-
-pub fn run_command_and_stream_err(
-    cmd_to_run: std::string::String,
-    args_for_cmd: &[&str],
-) -> Result<(), Error> {
-    let stdout = Command::new(cmd_to_run)
-        .args(args_for_cmd)
-        .env("GIT_EXTERNAL_DIFF", "difft")
-        .stderr(Stdio::piped())
-        .spawn()?
-        .stderr
-        .ok_or_else(|| Error::new(ErrorKind::Other, "Could not capture standard output."))?;
-
-    let reader = BufReader::new(stdout);
-
-    reader
-        .lines()
-        .map_while(Result::ok)
-        .filter(|line| line.contains("usb"))
-        .for_each(|line| println!("{}", line));
-
-    Ok(())
-}
+use crate::config_handler::read_config_file;
 
 pub fn run_command(
     cmd_to_run: std::string::String,
@@ -93,7 +49,7 @@ pub fn make_dir_if_not_exists(path: &Path) {
     if !path.exists() {
         println!("Creating Snapshots Directory: {:?}", path);
         fs::create_dir_all(path)
-            .expect(&format!("Could not create {:?} directory!", path.to_str()));
+            .unwrap_or_else(|_| panic!("Could not create {:?} directory!", path.to_str()));
     }
 }
 
@@ -126,6 +82,12 @@ pub fn try_detect_distro() -> String {
 }
 
 pub fn get_root_partition_device() -> String {
+    if let Ok(opts) = read_config_file() {
+        if !opts.root_partition.is_empty() {
+            return opts.root_partition;
+        }
+    }
+
     //df --output=source,fstype,target
 
     let df_args = vec!["--output=source,fstype,target"];
@@ -158,5 +120,42 @@ pub fn get_root_partition_device() -> String {
         }
     }
 
-    return String::from("");
+    String::from("")
+}
+
+#[repr(C)]
+struct Tm {
+    tm_sec: c_int,
+    tm_min: c_int,
+    tm_hour: c_int,
+    tm_mday: c_int,
+    tm_mon: c_int,
+    tm_year: c_int,
+    tm_wday: c_int,
+    tm_yday: c_int,
+    tm_isdst: c_int,
+    tm_gmtoff: c_long,
+    tm_zone: *const c_char,
+}
+
+extern "C" {
+    fn localtime_r(timep: *const i64, result: *mut Tm) -> *mut Tm;
+}
+
+/// Format seconds since the epoch as local time, e.g. `2024-03-01 14:05`.
+pub fn format_timestamp(seconds: u64) -> String {
+    let time = seconds as i64;
+    let mut tm: Tm = unsafe { std::mem::zeroed() };
+    if unsafe { localtime_r(&time, &mut tm) }.is_null() {
+        return seconds.to_string();
+    }
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
 }